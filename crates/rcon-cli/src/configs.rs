@@ -1,14 +1,31 @@
+use std::fmt;
 use std::fs::File;
 use std::collections::HashMap;
 
 use serde::Deserialize;
-use serde_json;
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Clone)]
 pub struct ServerConfig {
     pub host: String,
+    /// Not needed when `host` is a `unix://` address
+    #[serde(default)]
     pub port: u16,
     pub password: String,
+    /// Command to connect through instead of TCP, eg: `ssh bastion nc %h %p`
+    #[serde(default)]
+    pub proxy_command: Option<String>,
+}
+
+/// The password is redacted, so loaded configs can be logged.
+impl fmt::Debug for ServerConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerConfig")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("password", &"<redacted>")
+            .field("proxy_command", &self.proxy_command)
+            .finish()
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -63,21 +80,19 @@ fn load_config(config_file_path: &str, config_name: Option<String>) -> Option<Se
         if let Some(server_config) = config.configs.get(&name) {
             log::info!("Using config: {}", name);
             log::debug!("Server config: {:?}", server_config);
-            return Some(server_config.clone());
+            Some(server_config.clone())
         } else {
             log::error!("Config with name '{}' not found in config file.", name);
-            return None;
+            None
         }
+    } else if config.configs.len() == 1 {
+        let (name, server_config) = config.configs.iter().next().unwrap();
+        log::info!("No config name provided. Using the only available config: {}", name);
+        log::debug!("Server config: {:?}", server_config);
+        Some(server_config.clone())
     } else {
-        if config.configs.len() == 1 {
-            let (name, server_config) = config.configs.iter().next().unwrap();
-            log::info!("No config name provided. Using the only available config: {}", name);
-            log::debug!("Server config: {:?}", server_config);
-            return Some(server_config.clone());
-        } else {
-            log::error!("No config name provided. Please specify a config name.");
-            return None;
-        }   
+        log::error!("No config name provided. Please specify a config name.");
+        None
     }
 }
 
@@ -144,6 +159,43 @@ mod tests {
         assert_eq!(config.password, "admin");
     }
 
+    #[test]
+    fn test_load_config_with_proxy_command() {
+        let config_content = r#"{
+            "configs": {
+                "tunnelled": {
+                    "host": "10.0.0.5",
+                    "port": 27015,
+                    "password": "password123",
+                    "proxy_command": "ssh bastion nc %h %p"
+                }
+            }
+        }"#;
+
+        let temp_file = create_test_config_file(config_content);
+        let result = load_config(temp_file.path().to_str().unwrap(), None);
+
+        assert_eq!(result.unwrap().proxy_command.as_deref(), Some("ssh bastion nc %h %p"));
+    }
+
+    #[test]
+    fn test_load_config_unix_socket_without_port() {
+        let config_content = r#"{
+            "configs": {
+                "container": {
+                    "host": "unix:///run/rcon/rcon.sock",
+                    "password": "password123"
+                }
+            }
+        }"#;
+
+        let temp_file = create_test_config_file(config_content);
+        let config = load_config(temp_file.path().to_str().unwrap(), None).unwrap();
+
+        assert_eq!(config.host, "unix:///run/rcon/rcon.sock");
+        assert_eq!(config.port, 0);
+    }
+
     #[test]
     fn test_load_config_nonexistent_name() {
         let config_content = r#"{
@@ -224,10 +276,25 @@ mod tests {
             host: "example.com".to_string(),
             port: 8080,
             password: "secret".to_string(),
+            proxy_command: None,
         };
 
         let cloned = config.clone();
         assert_eq!(config.host, cloned.host);
         assert_eq!(config.password, cloned.password);
     }
+
+    #[test]
+    fn test_server_config_debug_redacts_password() {
+        let config = ServerConfig {
+            host: "example.com".to_string(),
+            port: 8080,
+            password: "secret".to_string(),
+            proxy_command: None,
+        };
+
+        let debug = format!("{:?}", config);
+        assert!(debug.contains("example.com"));
+        assert!(!debug.contains("secret"));
+    }
 }
\ No newline at end of file
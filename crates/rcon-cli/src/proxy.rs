@@ -0,0 +1,95 @@
+use rcon_tokio::errors::RconError;
+use rcon_tokio::Proxy;
+
+/// Checked in order, the first one set is used.
+const PROXY_ENV_VARS: [&str; 4] = ["ALL_PROXY", "all_proxy", "HTTPS_PROXY", "https_proxy"];
+const NO_PROXY_ENV_VARS: [&str; 2] = ["NO_PROXY", "no_proxy"];
+
+/// The proxy to reach `host` through: `--proxy` if given, otherwise the proxy environment variables
+/// unless `host` is excluded by `NO_PROXY`.
+pub fn proxy_for(host: &str, provided_proxy: &Option<String>) -> Result<Option<Proxy>, RconError> {
+    resolve_proxy(host, provided_proxy, |key| std::env::var(key).ok())
+}
+
+fn resolve_proxy(
+    host: &str,
+    provided_proxy: &Option<String>,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<Option<Proxy>, RconError> {
+    if let Some(url) = provided_proxy {
+        return Proxy::from_url(url).map(Some);
+    }
+
+    let first_set = |keys: &[&'static str]| keys.iter().find_map(|key| env(key).filter(|v| !v.is_empty()).map(|v| (*key, v)));
+
+    let Some((key, url)) = first_set(&PROXY_ENV_VARS) else {
+        return Ok(None);
+    };
+    if let Some((_, no_proxy)) = first_set(&NO_PROXY_ENV_VARS)
+        && is_excluded(host, &no_proxy)
+    {
+        log::debug!("{} is excluded by NO_PROXY, connecting directly", host);
+        return Ok(None);
+    }
+
+    log::debug!("Using proxy from {}", key);
+    Proxy::from_url(&url).map(Some)
+}
+
+/// Whether `host` matches an entry of a comma separated `NO_PROXY` list.
+/// Entries match the host itself and its subdomains, and `*` matches every host.
+fn is_excluded(host: &str, no_proxy: &str) -> bool {
+    let host = host.to_ascii_lowercase();
+    no_proxy
+        .split(',')
+        .map(|entry| entry.trim().trim_start_matches('.').to_ascii_lowercase())
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| entry == "*" || host == entry || host.ends_with(&format!(".{}", entry)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        move |key| vars.get(key).cloned()
+    }
+
+    #[test]
+    fn test_proxy_flag_overrides_env() {
+        let vars = env(&[("ALL_PROXY", "socks5h://bastion:1080")]);
+        let proxy = resolve_proxy("game.example.com", &Some("http://corp:3128".to_string()), vars).unwrap();
+
+        assert_eq!(proxy, Some(Proxy::http("corp".to_string(), 3128)));
+    }
+
+    #[test]
+    fn test_all_proxy_preferred_over_https_proxy() {
+        let vars = env(&[("https_proxy", "http://corp:3128"), ("ALL_PROXY", "socks5h://bastion:1080")]);
+        let proxy = resolve_proxy("game.example.com", &None, vars).unwrap();
+
+        assert_eq!(proxy, Some(Proxy::socks5("bastion".to_string(), 1080)));
+    }
+
+    #[test]
+    fn test_no_proxy_excludes_matching_hosts() {
+        let vars = env(&[("HTTPS_PROXY", "http://corp:3128"), ("NO_PROXY", "localhost, .lan")]);
+
+        assert_eq!(resolve_proxy("localhost", &None, &vars).unwrap(), None);
+        assert_eq!(resolve_proxy("minecraft.lan", &None, &vars).unwrap(), None);
+        assert!(resolve_proxy("game.example.com", &None, &vars).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_no_proxy_env_is_none() {
+        assert_eq!(resolve_proxy("game.example.com", &None, env(&[])).unwrap(), None);
+    }
+
+    #[test]
+    fn test_invalid_proxy_url_is_error() {
+        assert!(resolve_proxy("game.example.com", &None, env(&[("ALL_PROXY", "bastion:1080")])).is_err());
+    }
+}
@@ -0,0 +1,554 @@
+//! battleye.rs
+//!
+//! BattlEye RCon client, as used by ARMA and DayZ servers.
+//! https://www.battleye.com/downloads/BERConProtocol.txt
+//!
+//! Anatomy of a packet (UDP):
+//! | Field     | Type      | Size          |
+//! | --------- | --------- | ------------- |
+//! | Header    | 'B' 'E'   | 2 bytes       |
+//! | CRC32     | u32 (LE)  | 4 bytes       |
+//! | Separator | 0xFF      | 1 byte        |
+//! | Type      | u8        | 1 byte        |
+//! | Payload   | bytes     | 0.. bytes     |
+//! | --------- | --------- | ------------- |
+//!
+//! The checksum covers everything from the separator onwards.
+//! - Login (0x00): the client sends the password, the server replies 0x01 on success or 0x00 on failure.
+//! - Command (0x01): `seq | command`, answered with `seq | response`, or with several
+//!   `seq | 0x00 | count | index | part` packets when the response does not fit in one.
+//! - Server message (0x02): `seq | message`, which the client must acknowledge with `seq`.
+//!
+//! The server drops clients which send nothing for 45 seconds, so an empty command is sent as a keepalive.
+//! Once several keepalives in a row go unanswered, eg: after the server restarted, the session is closed.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::sync::{broadcast, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+use crate::{client_config::RconClientConfig, errors::RconError};
+
+const HEADER: [u8; 2] = *b"BE";
+const SEPARATOR: u8 = 0xFF;
+const HEADER_SIZE: usize = 7;
+
+const LOGIN: u8 = 0x00;
+const COMMAND: u8 = 0x01;
+const SERVER_MESSAGE: u8 = 0x02;
+
+const LOGIN_SUCCESS: u8 = 0x01;
+const MULTIPART_MARKER: u8 = 0x00;
+
+const MAX_DATAGRAM_SIZE: usize = 65_507;
+const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+/// Unanswered keepalives in a row after which the session is considered dead.
+const MISSED_KEEPALIVE_LIMIT: usize = 2;
+const EVENT_CHANNEL_CAPACITY: usize = 256;
+
+/// Builds a packet of `packet_type` carrying `payload`.
+fn build_packet(packet_type: u8, payload: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(2 + payload.len());
+    body.push(SEPARATOR);
+    body.push(packet_type);
+    body.extend_from_slice(payload);
+
+    let mut packet = Vec::with_capacity(HEADER_SIZE - 1 + body.len());
+    packet.extend_from_slice(&HEADER);
+    packet.extend_from_slice(&crc32fast::hash(&body).to_le_bytes());
+    packet.extend_from_slice(&body);
+    packet
+}
+
+/// Validates the header and checksum of a received packet.
+///
+/// ### Returns
+/// - The packet type and payload.
+fn parse_packet(buf: &[u8]) -> Result<(u8, &[u8]), RconError> {
+    if buf.len() < HEADER_SIZE + 1 {
+        return Err(RconError::Protocol(format!("[READ] packet too small: {}", buf.len())));
+    }
+    if buf[0..2] != HEADER || buf[6] != SEPARATOR {
+        return Err(RconError::Protocol("[READ] packet missing BattlEye header".to_string()));
+    }
+
+    let crc = u32::from_le_bytes(buf[2..6].try_into().unwrap());
+    if crc != crc32fast::hash(&buf[6..]) {
+        return Err(RconError::Protocol("[READ] packet checksum mismatch".to_string()));
+    }
+
+    Ok((buf[7], &buf[8..]))
+}
+
+#[derive(Debug)]
+struct PendingCommand {
+    tx: oneshot::Sender<String>,
+    parts: Vec<Option<Vec<u8>>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    closed: bool,
+    next_seq: u8,
+    pending: HashMap<u8, PendingCommand>,
+    last_message_seq: Option<u8>,
+}
+
+impl State {
+    /// Takes the next sequence number no pending command is using, as these wrap after 256 commands.
+    fn alloc_seq(&mut self) -> Option<u8> {
+        let seq = (0..=u8::MAX)
+            .map(|offset| self.next_seq.wrapping_add(offset))
+            .find(|seq| !self.pending.contains_key(seq))?;
+        self.next_seq = seq.wrapping_add(1);
+        Some(seq)
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    socket: UdpSocket,
+    state: StdMutex<State>,
+    events: broadcast::Sender<String>,
+    client_config: RconClientConfig,
+}
+
+#[derive(Debug)]
+struct Shared {
+    inner: Arc<Inner>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// A cloneable BattlEye RCon client. Commands from clones may be in flight concurrently.
+#[derive(Debug, Clone)]
+pub struct BattlEyeClient {
+    shared: Arc<Shared>,
+}
+
+impl BattlEyeClient {
+    /// Logs in to the server, then keeps the session alive in the background until the client is dropped.
+    pub async fn connect(client_config: RconClientConfig) -> Result<Self, RconError> {
+        Self::connect_with_keepalive(client_config, DEFAULT_KEEPALIVE_INTERVAL).await
+    }
+
+    pub(crate) async fn connect_with_keepalive(
+        client_config: RconClientConfig,
+        keepalive_interval: Duration,
+    ) -> Result<Self, RconError> {
+        let addr = lookup_host((client_config.address.as_str(), client_config.port)).await?
+            .next()
+            .ok_or_else(|| RconError::ClientError(format!("Could not resolve {}", client_config.address)))?;
+        // Bound to the address family of the server, an IPv4 socket cannot reach an IPv6 server
+        let local: IpAddr = if addr.is_ipv6() { Ipv6Addr::UNSPECIFIED.into() } else { Ipv4Addr::UNSPECIFIED.into() };
+        let socket = UdpSocket::bind((local, 0)).await?;
+        socket.connect(addr).await?;
+
+        login(&socket, &client_config).await?;
+        log::debug!("Logged in to BattlEye server at {}:{}", client_config.address, client_config.port);
+
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        let inner = Arc::new(Inner {
+            socket,
+            state: StdMutex::new(State::default()),
+            events,
+            client_config,
+        });
+
+        let tasks = vec![
+            tokio::spawn(read_loop(Arc::clone(&inner))),
+            tokio::spawn(keepalive_loop(Arc::clone(&inner), keepalive_interval)),
+        ];
+
+        Ok(BattlEyeClient { shared: Arc::new(Shared { inner, tasks }) })
+    }
+
+    /// Executes a command, waiting up to `io_timeout` for every part of its response.
+    pub async fn execute(&self, command: &str) -> Result<String, RconError> {
+        self.shared.inner.execute(command).await
+    }
+
+    /// Subscribes to messages broadcast by the server, eg: chat and player connections.
+    /// Only messages received after subscribing are delivered.
+    pub fn subscribe(&self) -> broadcast::Receiver<String> {
+        self.shared.inner.events.subscribe()
+    }
+
+    /// Whether the session has ended, after which every command fails.
+    pub fn is_closed(&self) -> bool {
+        self.shared.inner.state.lock().unwrap().closed
+    }
+}
+
+async fn login(socket: &UdpSocket, client_config: &RconClientConfig) -> Result<(), RconError> {
+    socket.send(&build_packet(LOGIN, client_config.password.as_bytes())).await?;
+
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        let n = timeout(client_config.io_timeout, socket.recv(&mut buf))
+            .await
+            .map_err(|_| RconError::Timeout)??;
+
+        match parse_packet(&buf[..n]) {
+            Ok((LOGIN, [LOGIN_SUCCESS])) => return Ok(()),
+            Ok((LOGIN, _)) => return Err(RconError::AuthFailed),
+            Ok((packet_type, _)) => log::debug!("Received packet type {:#04x} while waiting for login, ignoring", packet_type),
+            Err(e) => log::debug!("Ignoring invalid packet while waiting for login: {}", e),
+        }
+    }
+}
+
+impl Inner {
+    async fn execute(&self, command: &str) -> Result<String, RconError> {
+        log::debug!("Executing BattlEye command ({} bytes)", command.len());
+        let (tx, rx) = oneshot::channel();
+
+        let seq = {
+            let mut state = self.state.lock().unwrap();
+            if state.closed {
+                return Err(RconError::ClientError("Connection closed".to_string()));
+            }
+            let Some(seq) = state.alloc_seq() else {
+                return Err(RconError::ClientError("Too many commands in flight".to_string()));
+            };
+            state.pending.insert(seq, PendingCommand { tx, parts: Vec::new() });
+            seq
+        };
+        let _pending = PendingGuard { state: &self.state, seq };
+
+        let mut payload = vec![seq];
+        payload.extend_from_slice(command.as_bytes());
+        timeout(self.client_config.io_timeout, self.socket.send(&build_packet(COMMAND, &payload)))
+            .await
+            .map_err(|_| RconError::Timeout)??;
+
+        match timeout(self.client_config.io_timeout, rx).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => Err(RconError::ClientError("Connection closed".to_string())),
+            Err(_) => Err(RconError::Timeout),
+        }
+    }
+
+    /// Handles a command response, which may be one part of a multi-part response.
+    fn handle_response(&self, payload: &[u8]) {
+        let Some((&seq, body)) = payload.split_first() else {
+            log::debug!("Received command response without a sequence number, ignoring");
+            return;
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let Some(pending) = state.pending.get_mut(&seq) else {
+            log::debug!("Received response for sequence {} with no pending command, ignoring", seq);
+            return;
+        };
+
+        match body {
+            [MULTIPART_MARKER, count, index, part @ ..] if count > index => {
+                if pending.parts.len() != *count as usize {
+                    pending.parts = vec![None; *count as usize];
+                }
+                pending.parts[*index as usize] = Some(part.to_vec());
+                if pending.parts.iter().any(Option::is_none) {
+                    return;
+                }
+
+                let response: Vec<u8> = pending.parts.iter().flatten().flatten().copied().collect();
+                if let Some(pending) = state.pending.remove(&seq) {
+                    let _ = pending.tx.send(String::from_utf8_lossy(&response).into_owned());
+                }
+            },
+            _ => {
+                if let Some(pending) = state.pending.remove(&seq) {
+                    let _ = pending.tx.send(String::from_utf8_lossy(body).into_owned());
+                }
+            },
+        }
+    }
+
+    /// Acknowledges a server message, and delivers it to subscribers unless it is a resend.
+    async fn handle_server_message(&self, payload: &[u8]) {
+        let Some((&seq, body)) = payload.split_first() else {
+            log::debug!("Received server message without a sequence number, ignoring");
+            return;
+        };
+
+        if let Err(e) = self.socket.send(&build_packet(SERVER_MESSAGE, &[seq])).await {
+            log::warn!("Failed to acknowledge server message {}: {}", seq, e);
+        }
+
+        let is_resend = {
+            let mut state = self.state.lock().unwrap();
+            let is_resend = state.last_message_seq == Some(seq);
+            state.last_message_seq = Some(seq);
+            is_resend
+        };
+        if !is_resend {
+            let _ = self.events.send(String::from_utf8_lossy(body).into_owned());
+        }
+    }
+
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.pending.clear();
+    }
+}
+
+/// Removes a command from the pending set when it completes or is dropped.
+struct PendingGuard<'a> {
+    state: &'a StdMutex<State>,
+    seq: u8,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().pending.remove(&self.seq);
+    }
+}
+
+async fn read_loop(inner: Arc<Inner>) {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        let n = match inner.socket.recv(&mut buf).await {
+            Ok(n) => n,
+            Err(e) => {
+                log::warn!("BattlEye connection failed: {}", e);
+                inner.close();
+                return;
+            },
+        };
+
+        match parse_packet(&buf[..n]) {
+            Ok((COMMAND, payload)) => inner.handle_response(payload),
+            Ok((SERVER_MESSAGE, payload)) => inner.handle_server_message(payload).await,
+            Ok((packet_type, _)) => log::debug!("Received unexpected packet type {:#04x}, ignoring", packet_type),
+            Err(e) => log::debug!("Ignoring invalid packet: {}", e),
+        }
+    }
+}
+
+async fn keepalive_loop(inner: Arc<Inner>, interval: Duration) {
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    let mut missed = 0;
+    loop {
+        ticker.tick().await;
+        log::debug!("Sending BattlEye keepalive");
+        match inner.execute("").await {
+            Ok(_) => missed = 0,
+            Err(RconError::ClientError(_)) => return,
+            Err(RconError::Io(e)) => {
+                log::warn!("BattlEye keepalive could not be sent, closing session: {}", e);
+                inner.close();
+                return;
+            },
+            Err(e) => {
+                missed += 1;
+                if missed >= MISSED_KEEPALIVE_LIMIT {
+                    log::warn!("{} BattlEye keepalives in a row failed, closing session: {}", missed, e);
+                    inner.close();
+                    return;
+                }
+                log::warn!("BattlEye keepalive failed: {}", e);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    struct TestServer {
+        config: RconClientConfig,
+        keepalives: Arc<AtomicUsize>,
+        acks: Arc<AtomicUsize>,
+    }
+
+    /// A BattlEye stand-in. `multi` is answered in three parts out of order, `message` also pushes
+    /// a server message twice as if the first ack was lost, anything else is echoed.
+    async fn start_server() -> TestServer {
+        start_server_on(UdpSocket::bind("127.0.0.1:0").await.unwrap())
+    }
+
+    fn start_server_on(socket: UdpSocket) -> TestServer {
+        let addr = socket.local_addr().unwrap();
+        let keepalives = Arc::new(AtomicUsize::new(0));
+        let acks = Arc::new(AtomicUsize::new(0));
+
+        let (keepalive_count, ack_count) = (Arc::clone(&keepalives), Arc::clone(&acks));
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+            loop {
+                let (n, peer) = socket.recv_from(&mut buf).await.unwrap();
+                let (packet_type, payload) = parse_packet(&buf[..n]).unwrap();
+                let replies = match (packet_type, payload) {
+                    (LOGIN, b"pw") => vec![build_packet(LOGIN, &[LOGIN_SUCCESS])],
+                    (LOGIN, _) => vec![build_packet(LOGIN, &[0x00])],
+                    (SERVER_MESSAGE, _) => {
+                        ack_count.fetch_add(1, Ordering::SeqCst);
+                        vec![]
+                    },
+                    (COMMAND, [seq]) => {
+                        keepalive_count.fetch_add(1, Ordering::SeqCst);
+                        vec![build_packet(COMMAND, &[*seq])]
+                    },
+                    (COMMAND, [seq, b'm', b'u', b'l', b't', b'i']) => vec![
+                        build_packet(COMMAND, &[*seq, MULTIPART_MARKER, 3, 2, b'c']),
+                        build_packet(COMMAND, &[*seq, MULTIPART_MARKER, 3, 0, b'a']),
+                        build_packet(COMMAND, &[*seq, MULTIPART_MARKER, 3, 1, b'b']),
+                    ],
+                    (COMMAND, [seq, b'm', b'e', b's', b's', b'a', b'g', b'e']) => vec![
+                        build_packet(SERVER_MESSAGE, b"\x00Player connected"),
+                        build_packet(SERVER_MESSAGE, b"\x00Player connected"),
+                        build_packet(COMMAND, &[*seq]),
+                    ],
+                    (COMMAND, [seq, command @ ..]) => {
+                        let mut payload = vec![*seq];
+                        payload.extend_from_slice(command);
+                        vec![build_packet(COMMAND, &payload)]
+                    },
+                    _ => vec![],
+                };
+                for reply in replies {
+                    socket.send_to(&reply, peer).await.unwrap();
+                }
+            }
+        });
+
+        let config = RconClientConfig::new(addr.ip().to_string(), addr.port(), "pw".to_string())
+            .io_timeout(Duration::from_secs(1));
+        TestServer { config, keepalives, acks }
+    }
+
+    #[test]
+    fn parse_packet_round_trips_build_packet() {
+        let packet = build_packet(COMMAND, b"\x05players");
+
+        let (packet_type, payload) = parse_packet(&packet).unwrap();
+
+        assert_eq!(packet_type, COMMAND);
+        assert_eq!(payload, b"\x05players");
+    }
+
+    #[test]
+    fn parse_packet_rejects_bad_checksum() {
+        let mut packet = build_packet(COMMAND, b"\x05players");
+        let n = packet.len();
+        packet[n - 1] ^= 0xFF;
+
+        assert!(matches!(parse_packet(&packet), Err(RconError::Protocol(_))));
+    }
+
+    #[test]
+    fn alloc_seq_skips_sequence_numbers_in_use() {
+        let mut state = State { next_seq: 255, ..Default::default() };
+        let (tx, _) = oneshot::channel();
+        state.pending.insert(0, PendingCommand { tx, parts: Vec::new() });
+
+        assert_eq!(state.alloc_seq(), Some(255));
+        assert_eq!(state.alloc_seq(), Some(1));
+
+        for seq in 0..=u8::MAX {
+            let (tx, _) = oneshot::channel();
+            state.pending.insert(seq, PendingCommand { tx, parts: Vec::new() });
+        }
+        assert_eq!(state.alloc_seq(), None);
+    }
+
+    #[tokio::test]
+    async fn connect_fails_on_wrong_password() {
+        let server = start_server().await;
+        let config = RconClientConfig { password: "wrong".to_string(), ..server.config };
+
+        let res = BattlEyeClient::connect(config).await;
+        assert!(matches!(res, Err(RconError::AuthFailed)));
+    }
+
+    #[tokio::test]
+    async fn execute_returns_single_part_response() {
+        let server = start_server().await;
+        let client = BattlEyeClient::connect(server.config).await.unwrap();
+
+        assert_eq!(client.execute("players").await.unwrap(), "players");
+    }
+
+    #[tokio::test]
+    async fn execute_reassembles_multi_part_response() {
+        let server = start_server().await;
+        let client = BattlEyeClient::connect(server.config).await.unwrap();
+
+        assert_eq!(client.execute("multi").await.unwrap(), "abc");
+    }
+
+    #[tokio::test]
+    async fn server_messages_are_acknowledged_and_delivered_once() {
+        let server = start_server().await;
+        let client = BattlEyeClient::connect(server.config).await.unwrap();
+        let mut messages = client.subscribe();
+
+        client.execute("message").await.unwrap();
+
+        assert_eq!(messages.recv().await.unwrap(), "Player connected");
+        assert!(messages.try_recv().is_err());
+
+        // acks are counted by the server after the response has been sent
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(server.acks.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn connect_reaches_ipv6_servers() {
+        let Ok(socket) = UdpSocket::bind("[::1]:0").await else {
+            // No IPv6 loopback in this environment
+            return;
+        };
+        let server = start_server_on(socket);
+        let client = BattlEyeClient::connect(server.config).await.unwrap();
+
+        assert_eq!(client.execute("players").await.unwrap(), "players");
+    }
+
+    #[tokio::test]
+    async fn keepalive_closes_session_once_server_stops_answering() {
+        // Answers the login, then nothing else
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+            let (_, peer) = socket.recv_from(&mut buf).await.unwrap();
+            socket.send_to(&build_packet(LOGIN, &[LOGIN_SUCCESS]), peer).await.unwrap();
+            loop {
+                socket.recv_from(&mut buf).await.unwrap();
+            }
+        });
+        let config = RconClientConfig::new(addr.ip().to_string(), addr.port(), "pw".to_string())
+            .io_timeout(Duration::from_millis(20));
+        let client = BattlEyeClient::connect_with_keepalive(config, Duration::from_millis(20)).await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(client.is_closed());
+    }
+
+    #[tokio::test]
+    async fn keepalive_sends_empty_commands() {
+        let server = start_server().await;
+        let _client = BattlEyeClient::connect_with_keepalive(server.config, Duration::from_millis(20)).await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(server.keepalives.load(Ordering::SeqCst) >= 2);
+    }
+}
@@ -0,0 +1,383 @@
+//! blocking.rs
+//!
+//! A synchronous client over `std::net::TcpStream`, for build scripts and other small tools which
+//! should not need an async runtime. Enabled with the `blocking` feature, and usable without `async`.
+//!
+//! Packets are built, parsed, reassembled and retried by the same code as the async client. `io_timeout` becomes
+//! the socket read and write timeout, and zero durations disable the timeout instead. A failed read or write
+//! can leave part of a packet behind, so the connection is not used again until the client reconnects.
+
+use std::io::{ErrorKind, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::client_config::{RconClientConfig, ResponseFraming};
+use crate::common::{Direction, PacketType, Phase};
+use crate::packet::{build_packet, read_packet_blocking, Packet};
+use crate::rate_limit::RateLimiter;
+use crate::errors::{RconError, SendError};
+use crate::response::{check_auth_response, ResponseAssembler};
+use crate::retry::{Attempt, Attempts};
+
+const DEFAULT_NEXT_ID: i32 = 1;
+
+#[derive(Debug)]
+pub struct RconClient {
+    stream: TcpStream,
+    next_id: i32,
+    client_config: RconClientConfig,
+    rate_limiter: RateLimiter,
+    /// Set when a read or write fails, which can leave part of a packet on the stream.
+    needs_reconnect: bool,
+}
+
+impl RconClient {
+    /// Connects to `address:port` and authenticates.
+    pub fn connect(client_config: RconClientConfig) -> Result<Self, RconError> {
+        let stream = open_stream(&client_config)?;
+        let rate_limiter = RateLimiter::new(client_config.rate_limit.as_ref());
+        let mut client = RconClient { stream, next_id: DEFAULT_NEXT_ID, client_config, rate_limiter, needs_reconnect: false };
+        client.authenticate()?;
+
+        Ok(client)
+    }
+
+    /// Opens a new connection to the server, and authenticates again.
+    pub fn reconnect(&mut self) -> Result<(), RconError> {
+        log::debug!("Reconnecting client...");
+        self.stream = open_stream(&self.client_config)?;
+        self.authenticate()
+    }
+
+    pub fn authenticate(&mut self) -> Result<(), RconError> {
+        log::debug!("Starting authentication...");
+        // Stays set if this fails, so the auth response is never read as a command's
+        self.needs_reconnect = true;
+        let expected_id = self.write_packet(PacketType::ServerDataAuth, &self.client_config.password.clone().into_bytes())?;
+
+        loop {
+            let pkt = self.read_packet(Phase::Auth)?;
+            if check_auth_response(&pkt, expected_id, self.client_config.lenient_ids)? {
+                self.needs_reconnect = false;
+                return Ok(());
+            }
+            log::debug!("Received non-auth response packet with id {:?} while waiting for auth response, ignoring", pkt.id);
+        }
+    }
+
+    /// Executes a command, decoding the response with the configured `ResponseEncoding`.
+    pub fn execute(&mut self, command: &str) -> Result<String, RconError> {
+        let response = self.execute_bytes(command.as_bytes())?;
+        self.client_config.response_encoding.decode(response)
+    }
+
+    /// Executes a command, returning the raw bytes of the response.
+    pub fn execute_bytes(&mut self, command: &[u8]) -> Result<Vec<u8>, RconError> {
+        let mut results = Vec::new();
+        for chunk in self.client_config.dialect.split_command(command)? {
+            results.extend(self.execute_with_retry(chunk)?);
+        }
+        Ok(results)
+    }
+
+    fn execute_with_retry(&mut self, command: &[u8]) -> Result<Vec<u8>, RconError> {
+        let policy = self.client_config.retry_policy.clone();
+        let mut attempts = Attempts::new(
+            &policy,
+            self.client_config.auto_reconnect,
+            self.client_config.max_reconnect_attempts,
+            command,
+        );
+
+        loop {
+            match attempts.next() {
+                Attempt::Send => {},
+                Attempt::Reconnect(backoff) => {
+                    std::thread::sleep(backoff);
+                    if let Err(e) = self.reconnect() {
+                        attempts.reconnect_failed(e);
+                        continue;
+                    }
+                },
+                Attempt::GiveUp(e) => return Err(e),
+            }
+
+            self.rate_limiter.acquire_blocking(command)?;
+            let (cmd_id, mirror_id) = match self.send_command(command) {
+                Ok(ids) => ids,
+                Err(e) => {
+                    attempts.send_failed(e)?;
+                    continue;
+                },
+            };
+
+            match self.read_response(cmd_id, mirror_id) {
+                Ok(result) => return Ok(result),
+                Err(e) => attempts.response_failed(e)?,
+            }
+        }
+    }
+
+    /// Writes the command, followed by the mirror packet when using `ResponseFraming::Mirror`.
+    fn send_command(&mut self, command: &[u8]) -> Result<(i32, Option<i32>), SendError> {
+        if self.needs_reconnect {
+            return Err(SendError::unsent(
+                RconError::ClientError("Connection is not usable until the client reconnects".to_string())
+            ));
+        }
+        let cmd_id = self.alloc_id();
+        log::debug!("Executing command with id {:?} ({} bytes)", cmd_id, command.len());
+        let buf = build_packet(cmd_id, PacketType::ServerDataExecCommand, command, self.client_config.dialect)
+            .map_err(SendError::unsent)?;
+
+        let mut written = 0;
+        while written < buf.len() {
+            match self.stream.write(&buf[written..]) {
+                Ok(0) => {
+                    self.needs_reconnect = true;
+                    return Err(SendError { error: RconError::Io(ErrorKind::WriteZero.into()), reached_server: written > 0 });
+                },
+                Ok(n) => written += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) => {
+                    self.needs_reconnect = true;
+                    return Err(SendError { error: map_timeout(e), reached_server: written > 0 });
+                },
+            }
+        }
+        log::debug!("Sent {:?} packet with id: {:?}", PacketType::ServerDataExecCommand, cmd_id);
+
+        let mirror_id = match self.client_config.response_framing {
+            ResponseFraming::Mirror => Some(
+                self.write_packet(PacketType::ServerDataResponseValue, b"")
+                    .map_err(|error| SendError { error, reached_server: true })?
+            ),
+            _ => None,
+        };
+
+        Ok((cmd_id, mirror_id))
+    }
+
+    fn read_response(&mut self, cmd_id: i32, mirror_id: Option<i32>) -> Result<Vec<u8>, RconError> {
+        let framing = self.client_config.response_framing;
+        let mut response = ResponseAssembler::new(framing, self.client_config.dialect, cmd_id, mirror_id)
+            .lenient_ids(self.client_config.lenient_ids);
+
+        loop {
+            if framing == ResponseFraming::IdleTimeout && !self.wait_for_data(self.client_config.idle_timeout)? {
+                response.idle();
+                break;
+            }
+
+            if response.push(self.read_packet(Phase::Command)?) {
+                break;
+            }
+        }
+
+        Ok(response.finish())
+    }
+
+    /// Waits up to `t` for the server to send anything, without consuming it.
+    /// Peeking means a packet is never cut off part way through by the idle timeout.
+    fn wait_for_data(&mut self, t: Duration) -> Result<bool, RconError> {
+        self.stream.set_read_timeout(non_zero(t))?;
+        let res = self.stream.peek(&mut [0u8; 1]);
+        self.stream.set_read_timeout(non_zero(self.client_config.io_timeout))?;
+
+        let res = match res {
+            Ok(0) => Err(RconError::Io(ErrorKind::UnexpectedEof.into())),
+            Ok(_) => Ok(true),
+            Err(e) if is_timeout(&e) => Ok(false),
+            Err(e) => Err(e.into()),
+        };
+        self.needs_reconnect |= res.is_err();
+        res
+    }
+
+    fn alloc_id(&mut self) -> i32 {
+        let id = self.next_id;
+        self.next_id = if self.next_id >= i32::MAX - 10 { DEFAULT_NEXT_ID } else { self.next_id + 1 };
+        id
+    }
+
+    fn write_packet(&mut self, packet_type: PacketType, body: &[u8]) -> Result<i32, RconError> {
+        let id = self.alloc_id();
+        let buf = build_packet(id, packet_type, body, self.client_config.dialect)?;
+        if let Err(e) = self.stream.write_all(&buf) {
+            self.needs_reconnect = true;
+            return Err(map_timeout(e));
+        }
+        log::debug!("Sent {:?} packet with id: {:?}", packet_type, id);
+        Ok(id)
+    }
+
+    fn read_packet(&mut self, phase: Phase) -> Result<Packet, RconError> {
+        log::debug!("Waiting for packet...");
+        let res = read_packet_blocking(&mut self.stream, self.client_config.dialect, Direction::ServerToClient, phase)
+            .map_err(|e| match e {
+                RconError::Io(e) => map_timeout(e),
+                e => e,
+            });
+
+        // A timeout can cut a packet off part way through, leaving the rest to be misread as the next packet
+        self.needs_reconnect |= res.is_err();
+        if let Ok(pkt) = &res {
+            log::debug!("Received {:?} packet with id {:?} ({} bytes)", pkt.packet_type, pkt.id, pkt.body.len());
+        }
+        res
+    }
+}
+
+fn open_stream(config: &RconClientConfig) -> Result<TcpStream, RconError> {
+    if config.proxy.is_some() {
+        return Err(RconError::ClientError("Proxies are not supported by the blocking client".to_string()));
+    }
+    if config.unix_path().is_some() {
+        return Err(RconError::ClientError("unix:// addresses are not supported by the blocking client".to_string()));
+    }
+    let addrs: Vec<SocketAddr> = (config.address.as_str(), config.port).to_socket_addrs()?.collect();
+    let mut last_err = None;
+
+    for addr in addrs {
+        let res = match non_zero(config.io_timeout) {
+            Some(t) => TcpStream::connect_timeout(&addr, t),
+            None => TcpStream::connect(addr),
+        };
+        match res {
+            Ok(stream) => {
+                stream.set_read_timeout(non_zero(config.io_timeout))?;
+                stream.set_write_timeout(non_zero(config.io_timeout))?;
+                return Ok(stream);
+            },
+            Err(e) => last_err = Some(e),
+        }
+    }
+
+    Err(map_timeout(last_err.unwrap_or_else(|| ErrorKind::AddrNotAvailable.into())))
+}
+
+/// std rejects zero socket timeouts, which are taken to mean no timeout.
+fn non_zero(t: Duration) -> Option<Duration> {
+    (!t.is_zero()).then_some(t)
+}
+
+/// Timed out socket operations fail with `WouldBlock` on unix and `TimedOut` on windows.
+fn is_timeout(e: &std::io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+fn map_timeout(e: std::io::Error) -> RconError {
+    if is_timeout(&e) { RconError::Timeout } else { RconError::Io(e) }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+    use crate::retry::RetryPolicy;
+
+    /// A blocking stand-in server which echoes commands, except `slow` which is never answered,
+    /// and `half` which only gets the first half of its response.
+    /// Each connection is closed on the command after `commands_per_connection`, without answering it.
+    fn start_server(commands_per_connection: usize) -> RconClientConfig {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                thread::spawn(move || {
+                    let mut commands = 0;
+                    while let Ok(pkt) = read_packet_blocking(&mut stream, Default::default(), Direction::ClientToServer, Phase::Command) {
+                        if pkt.packet_type == PacketType::ServerDataExecCommand {
+                            if commands == commands_per_connection {
+                                return;
+                            }
+                            commands += 1;
+                        }
+
+                        let reply = match pkt.packet_type {
+                            PacketType::ServerDataAuth if pkt.body == b"pw" => (pkt.id, PacketType::ServerDataAuthResponse, vec![]),
+                            PacketType::ServerDataAuth => (-1, PacketType::ServerDataAuthResponse, vec![]),
+                            PacketType::ServerDataExecCommand if pkt.body == b"slow" => continue,
+                            PacketType::ServerDataExecCommand if pkt.body == b"half" => {
+                                let buf = build_packet(pkt.id, PacketType::ServerDataResponseValue, b"half", Default::default()).unwrap();
+                                stream.write_all(&buf[..buf.len() / 2]).unwrap();
+                                continue;
+                            },
+                            _ => (pkt.id, PacketType::ServerDataResponseValue, pkt.body),
+                        };
+                        let buf = build_packet(reply.0, reply.1, &reply.2, Default::default()).unwrap();
+                        stream.write_all(&buf).unwrap();
+                    }
+                });
+            }
+        });
+
+        RconClientConfig::new(addr.ip().to_string(), addr.port(), "pw".to_string())
+            .io_timeout(Duration::from_millis(500))
+            .idle_timeout(Duration::from_millis(20))
+    }
+
+    #[test]
+    fn execute_returns_response() {
+        let mut client = RconClient::connect(start_server(usize::MAX)).unwrap();
+
+        assert_eq!(client.execute("status").unwrap(), "status");
+        assert_eq!(client.execute("players").unwrap(), "players");
+    }
+
+    #[test]
+    fn connect_fails_on_wrong_password() {
+        let config = RconClientConfig { password: "wrong".to_string(), ..start_server(usize::MAX) };
+
+        assert!(matches!(RconClient::connect(config), Err(RconError::AuthFailed)));
+    }
+
+    #[test]
+    fn execute_times_out_waiting_for_response() {
+        let config = start_server(usize::MAX)
+            .response_framing(ResponseFraming::EmptyPacket)
+            .max_reconnect_attempts(1)
+            .io_timeout(Duration::from_millis(50));
+        let mut client = RconClient::connect(config).unwrap();
+
+        let err = client.execute("slow").unwrap_err();
+        assert!(matches!(err, RconError::Timeout), "{:?}", err);
+    }
+
+    #[test]
+    fn execute_fails_after_partial_response_without_auto_reconnect() {
+        let config = start_server(usize::MAX)
+            .io_timeout(Duration::from_millis(50));
+        let mut client = RconClient::connect(config).unwrap();
+
+        assert!(matches!(client.execute("half"), Err(RconError::Timeout)));
+        assert!(matches!(client.execute("status"), Err(RconError::ClientError(_))));
+    }
+
+    #[test]
+    fn execute_reconnects_after_partial_response() {
+        let config = start_server(usize::MAX)
+            .io_timeout(Duration::from_millis(50))
+            .retry_policy(RetryPolicy::new().initial_backoff(Duration::from_millis(1)).non_idempotent_prefix("half"))
+            .auto_reconnect(true);
+        let mut client = RconClient::connect(config).unwrap();
+
+        assert!(matches!(client.execute("half"), Err(RconError::Timeout)));
+        assert_eq!(client.execute("status").unwrap(), "status");
+    }
+
+    #[test]
+    fn execute_reconnects_after_connection_closes() {
+        let config = start_server(1)
+            .auto_reconnect(true)
+            .retry_policy(RetryPolicy::new().initial_backoff(Duration::from_millis(1)));
+        let mut client = RconClient::connect(config).unwrap();
+
+        assert_eq!(client.execute("first").unwrap(), "first");
+        assert_eq!(client.execute("second").unwrap(), "second");
+    }
+}
@@ -1,12 +1,48 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use bytes::BytesMut;
+use tokio::sync::broadcast;
+
 use crate::RconClientConfig;
+use crate::connect::Connector;
+use crate::packet::Packet;
+use crate::rate_limit::RateLimiter;
 
 const DEFAULT_NEXT_ID: i32 = 1;
+pub(crate) const EVENT_CHANNEL_CAPACITY: usize = 256;
+/// How many ids of abandoned commands to remember, so their late responses can be discarded.
+pub(crate) const STALE_ID_LIMIT: usize = 32;
 
 #[derive(Debug, Clone)]
 pub struct RconClient<S> {
     pub(crate) stream: S,
     pub(crate) next_id: i32,
     pub(crate) client_config: RconClientConfig,
+    pub(crate) connector: Option<Arc<dyn Connector<S>>>,
+    /// When a packet was last sent or received, used to decide when to send keepalive probes.
+    pub(crate) last_activity: Instant,
+    pub(crate) events: broadcast::Sender<Packet>,
+    /// Shared with clones of the client, and with the other connections of a pool or pipelined client.
+    pub(crate) rate_limiter: Arc<Mutex<RateLimiter>>,
+    /// Bytes read from the stream which do not yet make up a whole packet.
+    pub(crate) read_buf: BytesMut,
+    /// Packets which have not been completely written to the stream yet.
+    pub(crate) write_buf: BytesMut,
+    /// The id and length of each packet in `write_buf`.
+    pub(crate) write_frames: VecDeque<(i32, usize)>,
+    /// How many bytes of the first packet in `write_buf` have already been written.
+    pub(crate) frame_written: usize,
+    /// Set when a packet was left partly written, or authenticating failed or was cut short.
+    /// No commands are sent until the client reconnects.
+    pub(crate) needs_reconnect: bool,
+    /// The command and mirror ids of the command being executed, until its response has been read.
+    pub(crate) in_flight: Option<(i32, Option<i32>)>,
+    /// The ids of commands which were cancelled or failed before their response was read.
+    pub(crate) stale_ids: VecDeque<i32>,
+    #[cfg(feature = "metrics")]
+    pub(crate) stats: crate::stats::RconStats,
 }
 
 impl<S> RconClient<S> {
@@ -14,15 +50,102 @@ impl<S> RconClient<S> {
         RconClient {
             stream,
             next_id: DEFAULT_NEXT_ID,
-            client_config: RconClientConfig::default()
+            client_config: RconClientConfig::default(),
+            connector: None,
+            last_activity: Instant::now(),
+            events: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
+            rate_limiter: Default::default(),
+            read_buf: BytesMut::new(),
+            write_buf: BytesMut::new(),
+            write_frames: VecDeque::new(),
+            frame_written: 0,
+            needs_reconnect: false,
+            in_flight: None,
+            stale_ids: VecDeque::new(),
+            #[cfg(feature = "metrics")]
+            stats: Default::default(),
         }
     }
 
-    pub(crate) fn with_client_config(mut self, config: RconClientConfig) -> Self {
+    /// Sets the config used by the client, eg: when building a client from an existing stream with `new`.
+    pub fn with_client_config(mut self, config: RconClientConfig) -> Self {
+        self.rate_limiter = Arc::new(Mutex::new(RateLimiter::new(config.rate_limit.as_ref())));
         self.client_config = config;
         self
     }
 
+    /// Throttles the client's commands together with other clients using `rate_limiter`.
+    pub(crate) fn with_rate_limiter(mut self, rate_limiter: Arc<Mutex<RateLimiter>>) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
+    /// Sets the connector used to re-establish the stream when reconnecting.
+    /// Without a connector, `reconnect` and `auto_reconnect` fail.
+    pub fn with_connector<C: Connector<S> + 'static>(mut self, connector: C) -> Self {
+        self.connector = Some(Arc::new(connector));
+        self
+    }
+
+    /// Subscribes to packets which match no pending request, eg: chat and kill events pushed by Squad servers.
+    ///
+    /// The client only reads from the server while authenticating or executing a command, so events
+    /// are delivered then. Use `into_pipelined` to receive them as soon as they arrive.
+    pub fn subscribe(&self) -> broadcast::Receiver<Packet> {
+        self.events.subscribe()
+    }
+
+    pub(crate) fn publish(&self, pkt: Packet) {
+        if self.events.receiver_count() == 0 {
+            log::debug!("Received packet with id {:?} matching no request and no subscribers, ignoring", pkt.id);
+            return;
+        }
+        log::debug!("Publishing packet with id {:?} matching no request", pkt.id);
+        let _ = self.events.send(pkt);
+    }
+
+    /// Marks the ids of the command in flight as stale, as its response will no longer be read.
+    /// Returns whether there was such a command.
+    pub(crate) fn abandon_in_flight(&mut self) -> bool {
+        let Some((cmd_id, mirror_id)) = self.in_flight.take() else {
+            return false;
+        };
+        log::debug!("Command with id {:?} was abandoned before its response was read", cmd_id);
+        self.stale_ids.extend(std::iter::once(cmd_id).chain(mirror_id));
+        while self.stale_ids.len() > STALE_ID_LIMIT {
+            self.stale_ids.pop_front();
+        }
+        true
+    }
+
+    /// Drops the packets in the write buffer which no write has started on, keeping only a partly written one.
+    /// Returns the ids of the dropped packets.
+    pub(crate) fn drop_unstarted_packets(&mut self) -> Vec<i32> {
+        let keep = if self.frame_written > 0 { 1 } else { 0 };
+        let dropped: Vec<i32> = self.write_frames.drain(keep..).map(|(id, _)| id).collect();
+        let remaining = self.write_frames.front().map_or(0, |&(_, len)| len - self.frame_written);
+        self.write_buf.truncate(remaining);
+        if !dropped.is_empty() {
+            log::debug!("Dropped unsent packets with ids {:?}", dropped);
+        }
+        dropped
+    }
+
+    /// Whether a command was cancelled or failed partway, leaving the next command to clean up after it.
+    pub(crate) fn is_interrupted(&self) -> bool {
+        self.in_flight.is_some() || !self.write_buf.is_empty() || self.needs_reconnect
+    }
+
+    /// Forgets the buffers and pending commands of the old stream, once it has been replaced.
+    pub(crate) fn reset_stream_state(&mut self) {
+        self.read_buf.clear();
+        self.write_buf.clear();
+        self.write_frames.clear();
+        self.frame_written = 0;
+        self.in_flight = None;
+        self.stale_ids.clear();
+    }
+
     #[allow(dead_code)]
     pub(crate) fn with_next_id(mut self, next_id: i32) -> Self {
         self.next_id = next_id;
@@ -1,6 +1,15 @@
+use std::fmt;
 use std::time::Duration;
 
-#[derive(Default, Debug, Clone)]
+use crate::dialect::Dialect;
+use crate::encoding::ResponseEncoding;
+use crate::proxy::Proxy;
+use crate::rate_limit::RateLimit;
+use crate::retry::RetryPolicy;
+#[cfg(feature = "tls")]
+use crate::tls::TlsConfig;
+
+#[derive(Default, Clone)]
 pub struct RconClientConfig {
     pub address: String,
     pub port: u16,
@@ -9,22 +18,74 @@ pub struct RconClientConfig {
     pub idle_timeout: Duration,
     pub auto_reconnect: bool,
     pub max_reconnect_attempts: usize,
+    pub response_framing: ResponseFraming,
+    pub response_encoding: ResponseEncoding,
+    pub retry_policy: RetryPolicy,
+    pub dialect: Dialect,
+    pub lenient_ids: bool,
+    pub proxy: Option<Proxy>,
+    pub keepalive_interval: Option<Duration>,
+    pub keepalive_command: String,
+    pub rate_limit: Option<RateLimit>,
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsConfig>,
+}
+
+/// How the client decides that the response to a command is complete.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseFraming {
+    /// The response is complete once `idle_timeout` passes without receiving a packet.
+    #[default]
+    IdleTimeout,
+    /// An empty SERVERDATA_RESPONSE_VALUE is sent after the command, and the response is complete
+    /// once the server mirrors it back. Servers which do not mirror these packets will time out.
+    Mirror,
+    /// The response is complete once the server sends an empty packet for the command.
+    EmptyPacket,
 }
 
 const DEFAULT_IO_TIMOUT: Duration = Duration::from_secs(5);
 const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_millis(150);
 const MAX_RECONNECT_ATTEMPTS: usize = 3;
 
+const UNIX_SCHEME: &str = "unix://";
+
 impl RconClientConfig {
+    /// `address` may be a host name, an IP address, or a `unix:///path/to/socket` URL,
+    /// in which case `port` is ignored.
     pub fn new(address: String, port: u16, password: String) -> Self {
         Self {
-            address: address,
-            port: port,
-            password: password,
+            address,
+            port,
+            password,
             io_timeout: DEFAULT_IO_TIMOUT,
             idle_timeout: DEFAULT_IDLE_TIMEOUT,
             auto_reconnect: false,
             max_reconnect_attempts: MAX_RECONNECT_ATTEMPTS,
+            response_framing: ResponseFraming::default(),
+            response_encoding: ResponseEncoding::default(),
+            retry_policy: RetryPolicy::default(),
+            dialect: Dialect::default(),
+            lenient_ids: false,
+            proxy: None,
+            keepalive_interval: None,
+            keepalive_command: String::new(),
+            rate_limit: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    /// The socket path of a `unix://` address, or `None` for TCP addresses.
+    pub fn unix_path(&self) -> Option<&str> {
+        self.address.strip_prefix(UNIX_SCHEME)
+    }
+
+    /// The server as shown in logs, spans and metric labels: `address:port`, or the URL of a `unix://` address.
+    pub fn server(&self) -> String {
+        match self.unix_path() {
+            Some(_) => self.address.clone(),
+            None => format!("{}:{}", self.address, self.port),
         }
     }
 
@@ -36,6 +97,29 @@ impl RconClientConfig {
     /// while setting it too high may cause increased latency.
     pub fn idle_timeout(mut self, t: Duration) -> Self { self.idle_timeout = t; self }
 
+    /// How the client detects the end of a response. Defaults to `ResponseFraming::IdleTimeout`.
+    /// 
+    /// `idle_timeout` is only used by `ResponseFraming::IdleTimeout`, 
+    /// the other strategies wait up to `io_timeout` for each packet instead.
+    pub fn response_framing(mut self, f: ResponseFraming) -> Self { self.response_framing = f; self }
+
+    /// How `execute` decodes response bodies. Defaults to `ResponseEncoding::Utf8`, which fails on invalid UTF-8.
+    /// 
+    /// `execute_bytes` returns the raw bytes regardless.
+    pub fn response_encoding(mut self, e: ResponseEncoding) -> Self { self.response_encoding = e; self }
+
+    /// The game specific variant of the protocol spoken by the server. Defaults to `Dialect::Source`.
+    /// 
+    /// This controls the maximum command size, whether long commands are split,
+    /// and how multi-packet responses are reassembled.
+    pub fn dialect(mut self, d: Dialect) -> Self { self.dialect = d; self }
+
+    /// Accept responses whose id does not match the request, for servers which reply with id 0 or their own ids.
+    /// 
+    /// Packets are then matched to commands by order alone, so a late response may be returned for the next command.
+    /// Has no effect on `PipelinedClient`, which needs ids to route responses.
+    pub fn lenient_ids(mut self, v: bool) -> Self { self.lenient_ids = v; self }
+
     /// How long the client will wait for a response from the server before timing out and returning an error.
     pub fn io_timeout(mut self, t: Duration) -> Self { self.io_timeout = t; self }
 
@@ -45,4 +129,78 @@ impl RconClientConfig {
 
     /// The maximum number of times the client will attempt to reconnect and re-authenticate
     pub fn max_reconnect_attempts(mut self, v: usize) -> Self { self.max_reconnect_attempts = v; self }
-}
\ No newline at end of file
+
+    /// The backoff between reconnect attempts, and which commands are safe to send again 
+    /// after they may have reached the server.
+    pub fn retry_policy(mut self, v: RetryPolicy) -> Self { self.retry_policy = v; self }
+
+    /// Enables keepalive for connections idle this long: `RconClient::keepalive`, or the task started by
+    /// `RconClient::spawn_keepalive`, then sends a probe command,
+    /// and TCP connections also enable TCP keepalive so the OS detects dead peers.
+    pub fn keepalive(mut self, interval: Duration) -> Self { self.keepalive_interval = Some(interval); self }
+
+    /// The command sent as a keepalive probe. Defaults to an empty command, which servers answer with an empty response.
+    pub fn keepalive_command(mut self, c: String) -> Self { self.keepalive_command = c; self }
+
+    /// Throttles commands sent by `execute`, waiting before each command until the limit allows it.
+    /// Used by `RconClient` and the blocking client.
+    pub fn rate_limit(mut self, l: RateLimit) -> Self { self.rate_limit = Some(l); self }
+
+    /// Tunnels the connection through a SOCKS5 or HTTP CONNECT proxy. Used by `TcpConnector`,
+    /// and so by `TlsConnector`. The blocking client does not support proxies.
+    pub fn proxy(mut self, p: Proxy) -> Self { self.proxy = Some(p); self }
+
+    /// Certificates, SNI and pinning used by `TlsConnector`. Without this, `TlsConnector` verifies
+    /// the server against the Mozilla root certificates.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, t: TlsConfig) -> Self { self.tls = Some(t); self }
+}
+
+/// The password is redacted, so configs can be logged safely.
+impl fmt::Debug for RconClientConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = f.debug_struct("RconClientConfig");
+        s.field("address", &self.address)
+            .field("port", &self.port)
+            .field("password", &"<redacted>")
+            .field("io_timeout", &self.io_timeout)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("auto_reconnect", &self.auto_reconnect)
+            .field("max_reconnect_attempts", &self.max_reconnect_attempts)
+            .field("response_framing", &self.response_framing)
+            .field("response_encoding", &self.response_encoding)
+            .field("retry_policy", &self.retry_policy)
+            .field("dialect", &self.dialect)
+            .field("lenient_ids", &self.lenient_ids)
+            .field("proxy", &self.proxy)
+            .field("keepalive_interval", &self.keepalive_interval)
+            .field("keepalive_command", &self.keepalive_command)
+            .field("rate_limit", &self.rate_limit);
+        #[cfg(feature = "tls")]
+        s.field("tls", &self.tls);
+        s.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_redacts_password() {
+        let config = RconClientConfig::new("127.0.0.1".to_string(), 27015, "hunter2".to_string());
+        let debug = format!("{:?}", config);
+
+        assert!(debug.contains("127.0.0.1"));
+        assert!(!debug.contains("hunter2"), "{}", debug);
+    }
+
+    #[test]
+    fn server_includes_port_only_for_tcp() {
+        let tcp = RconClientConfig::new("127.0.0.1".to_string(), 27015, String::new());
+        let unix = RconClientConfig::new("unix:///run/rcon.sock".to_string(), 0, String::new());
+
+        assert_eq!(tcp.server(), "127.0.0.1:27015");
+        assert_eq!(unix.server(), "unix:///run/rcon.sock");
+    }
+}
@@ -1,21 +1,30 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+use bytes::Buf;
 use tokio::{
     io::{
         AsyncRead, 
+        AsyncReadExt,
         AsyncWrite,
         AsyncWriteExt,
     }, 
     time::timeout
 };
+use tokio_util::codec::Decoder;
 
+use crate::codec::RconCodec;
 use crate::packet::{
+    PACKET_OVERHEAD,
     Packet,
-    read_packet, 
     build_packet
 };
 use crate::{
     client::RconClient, 
-    common::PacketType, 
-    errors::RconError
+    common::{Direction, PacketType, Phase}, 
+    errors::RconError,
+    stats::Event,
+    trace,
 };
 
 impl<S: AsyncRead + AsyncWrite + Unpin> RconClient<S> {
@@ -24,7 +33,7 @@ impl<S: AsyncRead + AsyncWrite + Unpin> RconClient<S> {
     fn alloc_id(&mut self) -> i32 {
         let id = self.next_id;
         self.next_id = if self.next_id >= i32::MAX - 10 { 1 } else { self.next_id + 1 };
-        return id;
+        id
     }
 
     /// Writes a packet to the client stream with the given type and body.
@@ -36,24 +45,139 @@ impl<S: AsyncRead + AsyncWrite + Unpin> RconClient<S> {
     /// 
     /// ### Returns
     /// - The id of the packet that was written, or an error if the write failed or timed out.
-    pub(crate) async fn write_packet(&mut self, packet_type: PacketType, body: &str) -> Result<i32, RconError> {
+    pub(crate) async fn write_packet(&mut self, packet_type: PacketType, body: &[u8]) -> Result<i32, RconError> {
+        self.write_packet_within(packet_type, body, self.client_config.io_timeout).await
+    }
+
+    /// Like `write_packet`, waiting at most `io_timeout` instead of the config's.
+    pub(crate) async fn write_packet_within(&mut self, packet_type: PacketType, body: &[u8], io_timeout: Duration) -> Result<i32, RconError> {
+        let phase = if packet_type == PacketType::ServerDataAuth { Phase::Auth } else { Phase::Command };
+        self.finish_partial_write(io_timeout, phase).await?;
+        let id = self.queue_packet(packet_type, body)?;
+        self.flush_packets(io_timeout, phase).await?;
+        Ok(id)
+    }
+
+    /// Adds a packet to the write buffer, to be sent by the next `flush_packets`.
+    ///
+    /// ### Returns
+    /// - The id of the queued packet.
+    pub(crate) fn queue_packet(&mut self, packet_type: PacketType, body: &[u8]) -> Result<i32, RconError> {
         let id = self.alloc_id();
-        let buf = build_packet(id, packet_type.clone(), body)?;
-        timeout(self.client_config.io_timeout, self.stream.write_all(&buf))
-            .await
-            .map_err(|_| RconError::Timeout)??;
-        log::debug!("Sent {:?} packet with id: {:?}", packet_type, id);
+        let buf = build_packet(id, packet_type, body, self.client_config.dialect)?;
+        self.write_buf.extend_from_slice(&buf);
+        self.write_frames.push_back((id, buf.len()));
+        self.record(Event::PacketSent(buf.len()));
+        log::debug!("Queued {:?} packet with id: {:?}", packet_type, id);
         Ok(id)
     }
 
-    pub(crate) async fn read_packet(&mut self) -> Result<Packet, RconError> {
+    /// Writes out the write buffer, waiting at most `io_timeout`.
+    ///
+    /// Bytes are only removed from the buffer once written, so if this is cancelled or times out
+    /// partway through a packet, `finish_partial_write` knows how much of it is left.
+    pub(crate) async fn flush_packets(&mut self, io_timeout: Duration, phase: Phase) -> Result<(), RconError> {
+        let write = async {
+            while !self.write_buf.is_empty() {
+                let n = self.stream.write(&self.write_buf).await?;
+                if n == 0 {
+                    return Err(io::Error::from(io::ErrorKind::WriteZero));
+                }
+                self.write_buf.advance(n);
+                self.frames_written(n);
+            }
+            self.stream.flush().await
+        };
+
+        match timeout(io_timeout, write).await {
+            Ok(res) => res?,
+            Err(_) => {
+                self.record(Event::Timeout(phase));
+                return Err(RconError::Timeout);
+            },
+        }
+        self.last_activity = Instant::now();
+        Ok(())
+    }
+
+    /// Moves past the packets in `write_frames` which the last `n` written bytes completed.
+    fn frames_written(&mut self, n: usize) {
+        self.frame_written += n;
+        while let Some(&(_, len)) = self.write_frames.front() {
+            if self.frame_written < len {
+                break;
+            }
+            self.frame_written -= len;
+            self.write_frames.pop_front();
+        }
+    }
+
+    /// Gets the stream ready for the next packet, after a write which was cancelled or failed.
+    ///
+    /// Packets which were never started are dropped, so they are not sent along with the next request.
+    /// A packet which was partly written is finished. If that fails, the stream is left mid packet and
+    /// the client has to reconnect before sending another command.
+    ///
+    /// ### Returns
+    /// - The ids of the dropped packets.
+    pub(crate) async fn finish_partial_write(&mut self, io_timeout: Duration, phase: Phase) -> Result<Vec<i32>, RconError> {
+        let dropped = self.drop_unstarted_packets();
+        if !self.write_buf.is_empty() {
+            log::debug!("Finishing partly written packet");
+            if let Err(e) = self.flush_packets(io_timeout, phase).await {
+                self.needs_reconnect = true;
+                return Err(e);
+            }
+        }
+        Ok(dropped)
+    }
+
+    /// Reads a packet sent by the server, decoding its type for the connection's current `phase`.
+    pub(crate) async fn read_packet(&mut self, phase: Phase) -> Result<Packet, RconError> {
+        self.read_packet_within(phase, self.client_config.io_timeout).await
+    }
+
+    /// Like `read_packet`, waiting at most `io_timeout` instead of the config's.
+    pub(crate) async fn read_packet_within(&mut self, phase: Phase, io_timeout: Duration) -> Result<Packet, RconError> {
+        self.read_packet_from(Direction::ServerToClient, phase, io_timeout).await
+    }
+
+    /// Reads a packet sent by a client, for tests which stand in for the server.
+    #[cfg(test)]
+    pub(crate) async fn read_request(&mut self) -> Result<Packet, RconError> {
+        self.read_packet_from(Direction::ClientToServer, Phase::Command, self.client_config.io_timeout).await
+    }
+
+    async fn read_packet_from(&mut self, direction: Direction, phase: Phase, io_timeout: Duration) -> Result<Packet, RconError> {
         log::debug!("Waiting for packet...");
-        let res = timeout(self.client_config.io_timeout, read_packet(&mut self.stream))
-            .await
-            .map_err(|_| RconError::Timeout)?;
+        let res = timeout(io_timeout, self.read_frame(direction, phase)).await;
+        let Ok(res) = res else {
+            self.record(Event::Timeout(phase));
+            return Err(RconError::Timeout);
+        };
+
+        if let Ok(pkt) = &res {
+            trace::packet_received(pkt);
+            self.record(Event::PacketReceived(pkt.body.len() + PACKET_OVERHEAD));
+            self.last_activity = Instant::now();
+        }
+        res
+    }
 
-        log::debug!("Received packet: {:?}", res);
-        return res;
+    /// Reads into the read buffer until it holds a whole packet.
+    ///
+    /// Cancel safe: bytes stay in the buffer until a whole packet has been read, so a read which is
+    /// cancelled partway through a packet resumes where it left off.
+    async fn read_frame(&mut self, direction: Direction, phase: Phase) -> Result<Packet, RconError> {
+        let mut codec = RconCodec::new().dialect(self.client_config.dialect).direction(direction).phase(phase);
+        loop {
+            if let Some(pkt) = codec.decode(&mut self.read_buf)? {
+                return Ok(pkt);
+            }
+            if self.stream.read_buf(&mut self.read_buf).await? == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+        }
     }
 }
 
@@ -61,7 +185,7 @@ impl<S: AsyncRead + AsyncWrite + Unpin> RconClient<S> {
 mod tests {
     use std::time::Duration;
 
-    use crate::RconClientConfig;
+    use crate::{RconClientConfig, dialect::Dialect};
 
     use super::*;
 
@@ -81,8 +205,8 @@ mod tests {
         let ptype = PacketType::ServerDataAuth;
         let pw = "pw";
 
-        let id = client.write_packet(ptype.clone(), pw).await.unwrap();
-        let expected = build_packet(id, ptype, pw).unwrap();
+        let id = client.write_packet(ptype, pw.as_bytes()).await.unwrap();
+        let expected = build_packet(id, ptype, pw.as_bytes(), Dialect::Source).unwrap();
 
         let mut received: Vec<u8> = vec![0u8; expected.len()];
         server_stream.read_exact(&mut received).await.unwrap();
@@ -98,8 +222,8 @@ mod tests {
         let ptype = PacketType::ServerDataAuth;
         let pw = "";
 
-        let id = client.write_packet(ptype.clone(), pw).await.unwrap();
-        let expected = build_packet(id, ptype, pw).unwrap();
+        let id = client.write_packet(ptype, pw.as_bytes()).await.unwrap();
+        let expected = build_packet(id, ptype, pw.as_bytes(), Dialect::Source).unwrap();
 
         let mut received: Vec<u8> = vec![0u8; expected.len()];
         server_stream.read_exact(&mut received).await.unwrap();
@@ -112,18 +236,14 @@ mod tests {
         let (client_stream, mut server_stream) = duplex(MAX_BUF_SIZE);
         let mut client = RconClient::new(client_stream);
 
-        let bytes = build_packet(123, PacketType::ServerDataAuthResponse, "ok").unwrap();
+        let bytes = build_packet(123, PacketType::ServerDataAuthResponse, b"ok", Dialect::Source).unwrap();
         server_stream.write_all(&bytes).await.unwrap();
 
-        let packet = client.read_packet().await.unwrap();
-
-        // Hack
-        let pkt_type_i32: i32 = packet.packet_type.into();
-        let expected_type: i32 = PacketType::ServerDataAuthResponse.into();
+        let packet = client.read_packet(Phase::Auth).await.unwrap();
 
         assert_eq!(packet.id, 123);
-        assert_eq!(pkt_type_i32, expected_type);
-        assert_eq!(packet.body, "ok");
+        assert_eq!(packet.packet_type, PacketType::ServerDataAuthResponse);
+        assert_eq!(packet.body, b"ok");
     }
 
     #[tokio::test]
@@ -135,7 +255,7 @@ mod tests {
                 ..Default::default()
             });
 
-        let res = client.read_packet().await;
+        let res = client.read_packet(Phase::Command).await;
         assert!(matches!(res, Err(RconError::Timeout)));
     }
 
@@ -152,13 +272,112 @@ mod tests {
         // build a body that's allowed by your 511 char limit but large enough to fill duplex
         let body = "a".repeat(200); // tune if needed
         let err = client
-            .write_packet(PacketType::ServerDataAuth, &body)
+            .write_packet(PacketType::ServerDataAuth, body.as_bytes())
             .await
             .unwrap_err();
 
         assert!(matches!(err, RconError::Timeout));
     }
 
+    #[tokio::test]
+    async fn read_packet_resumes_after_being_cancelled_mid_packet() {
+        let (client_stream, mut server_stream) = duplex(MAX_BUF_SIZE);
+        let mut client = RconClient::new(client_stream)
+            .with_client_config(RconClientConfig {
+                io_timeout: Duration::from_secs(1),
+                ..Default::default()
+            });
+
+        let bytes = build_packet(7, PacketType::ServerDataResponseValue, b"hello", Dialect::Source).unwrap();
+        server_stream.write_all(&bytes[..6]).await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(20), client.read_packet(Phase::Command)).await.is_err());
+
+        server_stream.write_all(&bytes[6..]).await.unwrap();
+        let packet = client.read_packet(Phase::Command).await.unwrap();
+
+        assert_eq!(packet.id, 7);
+        assert_eq!(packet.body, b"hello");
+    }
+
+    #[tokio::test]
+    async fn write_packet_finishes_cancelled_packet_before_the_next() {
+        let (client_stream, server_stream) = duplex(8);
+        let mut client = RconClient::new(client_stream)
+            .with_client_config(RconClientConfig {
+                io_timeout: Duration::from_secs(1),
+                ..Default::default()
+            });
+
+        // The peer is not reading yet, so only the first 8 bytes fit
+        let cancelled = tokio::time::timeout(
+            Duration::from_millis(20),
+            client.write_packet(PacketType::ServerDataExecCommand, b"first"),
+        ).await;
+        assert!(cancelled.is_err());
+
+        let server = tokio::spawn(async move {
+            let mut server_client = RconClient::new(server_stream)
+                .with_client_config(RconClientConfig {
+                    io_timeout: Duration::from_secs(1),
+                    ..Default::default()
+                });
+            let first = server_client.read_request().await.unwrap();
+            let second = server_client.read_request().await.unwrap();
+            (first.body, second.body)
+        });
+        client.write_packet(PacketType::ServerDataExecCommand, b"second").await.unwrap();
+
+        assert_eq!(server.await.unwrap(), (b"first".to_vec(), b"second".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn write_packet_drops_packets_a_cancelled_write_never_started() {
+        let (client_stream, server_stream) = duplex(8);
+        let mut client = RconClient::new(client_stream)
+            .with_client_config(RconClientConfig {
+                io_timeout: Duration::from_secs(1),
+                ..Default::default()
+            });
+
+        client.queue_packet(PacketType::ServerDataExecCommand, b"first").unwrap();
+        client.queue_packet(PacketType::ServerDataExecCommand, b"never sent").unwrap();
+        let cancelled = tokio::time::timeout(Duration::from_millis(20), client.flush_packets(Duration::from_secs(1), Phase::Command)).await;
+        assert!(cancelled.is_err());
+
+        let server = tokio::spawn(async move {
+            let mut server_client = RconClient::new(server_stream)
+                .with_client_config(RconClientConfig {
+                    io_timeout: Duration::from_millis(100),
+                    ..Default::default()
+                });
+            let first = server_client.read_request().await.unwrap();
+            let second = server_client.read_request().await.unwrap();
+            assert!(server_client.read_request().await.is_err());
+            (first.body, second.body)
+        });
+        client.write_packet(PacketType::ServerDataExecCommand, b"second").await.unwrap();
+
+        assert_eq!(server.await.unwrap(), (b"first".to_vec(), b"second".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn write_packet_needs_reconnect_when_partial_packet_cannot_be_finished() {
+        let (client_stream, _server_stream) = duplex(8);
+        let mut client = RconClient::new(client_stream)
+            .with_client_config(RconClientConfig {
+                io_timeout: Duration::from_millis(10),
+                ..Default::default()
+            });
+
+        let first = client.write_packet(PacketType::ServerDataExecCommand, b"first").await;
+        assert!(matches!(first, Err(RconError::Timeout)));
+        let second = client.write_packet(PacketType::ServerDataExecCommand, b"second").await;
+        assert!(matches!(second, Err(RconError::Timeout)));
+
+        assert!(client.needs_reconnect);
+        assert_eq!(client.write_frames.len(), 1);
+    }
+
     #[tokio::test]
     async fn alloc_id_wraps_near_i32_max() {
         let (duplex_client, _) = duplex(MAX_BUF_SIZE);
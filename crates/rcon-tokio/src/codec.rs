@@ -0,0 +1,154 @@
+//! codec.rs
+//!
+//! The packet framing of packet.rs as a `tokio_util::codec` `Decoder` and `Encoder`, so that
+//! `Framed<TcpStream, RconCodec>` can be used as a `Stream` of received packets and a `Sink` of packets to send.
+//!
+//! The codec decodes packets as sent by the peer. A client reading server packets should move the codec
+//! to `Phase::Command` once authenticated, since packet type 2 only means SERVERDATA_AUTH_RESPONSE before then.
+
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::common::{Direction, Phase};
+use crate::packet::{encode_packet, parse_payload, payload_size, Packet, SIZE_FIELD_SIZE};
+use crate::{dialect::Dialect, errors::RconError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RconCodec {
+    pub dialect: Dialect,
+    /// The direction of the packets being decoded.
+    pub direction: Direction,
+    pub phase: Phase,
+}
+
+impl Default for RconCodec {
+    /// A codec for a client, decoding packets sent by the server during authentication.
+    fn default() -> Self {
+        Self {
+            dialect: Dialect::default(),
+            direction: Direction::ServerToClient,
+            phase: Phase::Auth,
+        }
+    }
+}
+
+impl RconCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A codec for a server, decoding packets sent by clients.
+    pub fn server() -> Self {
+        Self::default().direction(Direction::ClientToServer)
+    }
+
+    pub fn dialect(mut self, d: Dialect) -> Self { self.dialect = d; self }
+
+    pub fn direction(mut self, d: Direction) -> Self { self.direction = d; self }
+
+    pub fn phase(mut self, p: Phase) -> Self { self.phase = p; self }
+}
+
+impl Decoder for RconCodec {
+    type Item = Packet;
+    type Error = RconError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Packet>, RconError> {
+        let Some(size_bytes) = src.get(..SIZE_FIELD_SIZE) else {
+            return Ok(None);
+        };
+        let size = payload_size(size_bytes.try_into().unwrap(), self.dialect)?;
+
+        let frame_size = SIZE_FIELD_SIZE + size;
+        if src.len() < frame_size {
+            src.reserve(frame_size - src.len());
+            return Ok(None);
+        }
+
+        let packet = parse_payload(&src[SIZE_FIELD_SIZE..frame_size], self.direction, self.phase)?;
+        src.advance(frame_size);
+        Ok(Some(packet))
+    }
+}
+
+impl Encoder<Packet> for RconCodec {
+    type Error = RconError;
+
+    fn encode(&mut self, packet: Packet, dst: &mut BytesMut) -> Result<(), RconError> {
+        Encoder::<&Packet>::encode(self, &packet, dst)
+    }
+}
+
+impl Encoder<&Packet> for RconCodec {
+    type Error = RconError;
+
+    fn encode(&mut self, packet: &Packet, dst: &mut BytesMut) -> Result<(), RconError> {
+        encode_packet(packet.id, packet.packet_type, &packet.body, self.dialect, dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::io::duplex;
+    use tokio_util::codec::Framed;
+
+    use super::*;
+    use crate::common::PacketType;
+    use crate::packet::build_packet;
+
+    fn packet(id: i32, packet_type: PacketType, body: &[u8]) -> Packet {
+        Packet { id, packet_type, body: body.to_vec() }
+    }
+
+    #[test]
+    fn decode_waits_for_a_whole_packet() {
+        let bytes = build_packet(1, PacketType::ServerDataResponseValue, b"hello", Dialect::Source).unwrap();
+        let mut codec = RconCodec::new().phase(Phase::Command);
+        let mut src = BytesMut::new();
+
+        for &b in &bytes[..bytes.len() - 1] {
+            src.extend_from_slice(&[b]);
+            assert_eq!(codec.decode(&mut src).unwrap(), None);
+        }
+        src.extend_from_slice(&bytes[bytes.len() - 1..]);
+
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(packet(1, PacketType::ServerDataResponseValue, b"hello")));
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn decode_rejects_oversized_packets_before_buffering_them() {
+        let mut src = BytesMut::from(&(4097i32).to_le_bytes()[..]);
+
+        assert!(matches!(RconCodec::new().decode(&mut src), Err(RconError::Protocol(_))));
+    }
+
+    #[test]
+    fn encode_matches_build_packet() {
+        let mut dst = BytesMut::new();
+
+        RconCodec::new().encode(packet(7, PacketType::ServerDataExecCommand, b"status"), &mut dst).unwrap();
+
+        assert_eq!(&dst[..], build_packet(7, PacketType::ServerDataExecCommand, b"status", Dialect::Source).unwrap());
+    }
+
+    #[tokio::test]
+    async fn framed_client_and_server_exchange_packets() {
+        let (client_stream, server_stream) = duplex(4096);
+        let mut client = Framed::new(client_stream, RconCodec::new());
+        let mut server = Framed::new(server_stream, RconCodec::server());
+
+        client.send(packet(1, PacketType::ServerDataAuth, b"pw")).await.unwrap();
+        let auth = server.next().await.unwrap().unwrap();
+        assert_eq!(auth, packet(1, PacketType::ServerDataAuth, b"pw"));
+
+        server.send(packet(1, PacketType::ServerDataAuthResponse, b"")).await.unwrap();
+        let auth_response = client.next().await.unwrap().unwrap();
+        assert_eq!(auth_response.packet_type, PacketType::ServerDataAuthResponse);
+
+        client.codec_mut().phase = Phase::Command;
+        client.send(packet(2, PacketType::ServerDataExecCommand, b"status")).await.unwrap();
+        assert_eq!(server.next().await.unwrap().unwrap().packet_type, PacketType::ServerDataExecCommand);
+    }
+}
@@ -1,30 +1,72 @@
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum PacketType {
     ServerDataResponseValue,
     ServerDataAuthResponse,
     ServerDataExecCommand,
     ServerDataAuth,
+    /// A type value with no meaning for the packet's direction and connection phase.
+    Unknown(i32),
 }
 
-impl Into<i32> for PacketType {
-    fn into(self) -> i32 {
-        match self {
+/// Which peer sent a packet. Type 2 is SERVERDATA_EXECCOMMAND from a client, but SERVERDATA_AUTH_RESPONSE from a server.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+/// Where the connection is in its lifecycle. Servers only send SERVERDATA_AUTH_RESPONSE while a client authenticates.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Phase {
+    Auth,
+    Command,
+}
+
+impl From<PacketType> for i32 {
+    fn from(value: PacketType) -> i32 {
+        match value {
             PacketType::ServerDataAuth => 3,
             PacketType::ServerDataAuthResponse => 2,
             PacketType::ServerDataExecCommand => 2,
             PacketType::ServerDataResponseValue => 0,
+            PacketType::Unknown(value) => value,
         }
     }
 }
 
 impl PacketType {
-    pub fn from_i32(value: i32, is_auth: bool) -> PacketType {
-        match value {
-            3 => PacketType::ServerDataAuth,
-            2 if is_auth => PacketType::ServerDataAuthResponse,
-            2 => PacketType::ServerDataExecCommand,
-            0 => PacketType::ServerDataResponseValue,
-            _ => panic!("Unexpected value to parse")
+    /// Decodes a packet type sent in `direction` during `phase`.
+    /// Some servers answer commands with type 2 instead of 0, so after authentication a server's
+    /// type 2 decodes as `PacketType::ServerDataResponseValue`.
+    /// Values which are not valid there decode as `PacketType::Unknown`.
+    pub fn decode(value: i32, direction: Direction, phase: Phase) -> PacketType {
+        match (direction, phase, value) {
+            (_, _, 0) => PacketType::ServerDataResponseValue,
+            (Direction::ServerToClient, Phase::Command, 2) => PacketType::ServerDataResponseValue,
+            (Direction::ClientToServer, _, 2) => PacketType::ServerDataExecCommand,
+            (Direction::ClientToServer, _, 3) => PacketType::ServerDataAuth,
+            (Direction::ServerToClient, Phase::Auth, 2) => PacketType::ServerDataAuthResponse,
+            (_, _, value) => PacketType::Unknown(value),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_interprets_type_2_by_direction() {
+        assert_eq!(PacketType::decode(2, Direction::ClientToServer, Phase::Command), PacketType::ServerDataExecCommand);
+        assert_eq!(PacketType::decode(2, Direction::ServerToClient, Phase::Auth), PacketType::ServerDataAuthResponse);
+        assert_eq!(PacketType::decode(2, Direction::ServerToClient, Phase::Command), PacketType::ServerDataResponseValue);
+    }
+
+    #[test]
+    fn decode_returns_unknown_for_values_invalid_in_context() {
+        assert_eq!(PacketType::decode(3, Direction::ServerToClient, Phase::Auth), PacketType::Unknown(3));
+        assert_eq!(PacketType::decode(-7, Direction::ClientToServer, Phase::Command), PacketType::Unknown(-7));
+        assert_eq!(i32::from(PacketType::Unknown(-7)), -7);
+    }
+}
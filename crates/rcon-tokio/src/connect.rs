@@ -1,43 +1,138 @@
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
 
-use crate::client_config;
-use crate::{client::RconClient, errors::RconError, packet::Packet, common::PacketType};
+use crate::client_config::{self, RconClientConfig};
+use crate::keepalive::set_tcp_keepalive;
+use crate::net::{NetConnector, NetStream};
+use crate::response::check_auth_response;
+use crate::stats::Event;
+use crate::trace;
+use crate::{client::RconClient, errors::RconError, packet::Packet, common::{PacketType, Phase}};
+
+pub type ConnectFuture<'a, S> = Pin<Box<dyn Future<Output = Result<S, RconError>> + Send + 'a>>;
+
+/// Opens the stream a client runs the RCON protocol over.
+/// 
+/// Clients hold on to their connector, and use it to re-establish the stream on reconnect.
+/// Any `Fn(&RconClientConfig) -> Future<Output = Result<S, RconError>>` is also a connector.
+pub trait Connector<S>: Send + Sync {
+    fn connect<'a>(&'a self, config: &'a RconClientConfig) -> ConnectFuture<'a, S>;
+}
+
+impl<S> fmt::Debug for dyn Connector<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Connector")
+    }
+}
+
+impl<S, F, Fut> Connector<S> for F
+where
+    F: Fn(&RconClientConfig) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<S, RconError>> + Send + 'static,
+{
+    fn connect<'a>(&'a self, config: &'a RconClientConfig) -> ConnectFuture<'a, S> {
+        Box::pin(self(config))
+    }
+}
+
+/// Connects to `address:port` over plain TCP, through the config's `proxy` if set.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TcpConnector;
+
+impl Connector<TcpStream> for TcpConnector {
+    fn connect<'a>(&'a self, config: &'a RconClientConfig) -> ConnectFuture<'a, TcpStream> {
+        Box::pin(async move {
+            let stream = match &config.proxy {
+                Some(proxy) => proxy.tunnel(&config.address, config.port, config.io_timeout).await?,
+                None => TcpStream::connect((config.address.as_str(), config.port)).await?,
+            };
+            if let Some(interval) = config.keepalive_interval {
+                set_tcp_keepalive(&stream, interval)?;
+            }
+            Ok(stream)
+        })
+    }
+}
 
-impl RconClient<TcpStream> {
+impl RconClient<NetStream> {
+    /// Connects over TCP, or a Unix domain socket for `unix://` addresses, and authenticates.
+    /// Use `connect_with(client_config, TcpConnector)` for a client over a plain `TcpStream`.
     pub async fn connect(
         client_config: client_config::RconClientConfig
     ) -> Result<Self, RconError> {
-        let stream = TcpStream::connect((client_config.address.as_str(), client_config.port)).await?;
-        let mut client = RconClient::new(stream).with_client_config(client_config);
-        client.authenticate().await?;
-
-        Ok(client)
+        RconClient::connect_with(client_config, NetConnector).await
     }
 }
 
 impl<S: AsyncRead + AsyncWrite + Unpin> RconClient<S> {
+    /// Opens a stream with `connector` and authenticates over it.
+    /// The connector is kept, so the client can reconnect over the same kind of transport.
+    pub async fn connect_with<C: Connector<S> + 'static>(
+        client_config: RconClientConfig,
+        connector: C,
+    ) -> Result<Self, RconError> {
+        let span = trace::connect_span(&client_config);
+        trace::in_span(span, async move {
+            let stream = connector.connect(&client_config).await?;
+            let mut client = RconClient::new(stream)
+                .with_client_config(client_config)
+                .with_connector(connector);
+            client.authenticate().await?;
+
+            Ok(client)
+        }).await
+    }
+
+    /// Re-establishes the stream through the client's connector, and authenticates again.
+    pub async fn reconnect(&mut self) -> Result<(), RconError> {
+        let connector = self.connector.clone()
+            .ok_or_else(|| RconError::ClientError("Cannot reconnect a client without a connector".to_string()))?;
+
+        log::debug!("Reconnecting client...");
+        self.record(Event::Reconnect);
+        let span = trace::connect_span(&self.client_config);
+        trace::in_span(span, async {
+            self.needs_reconnect = true;
+            self.stream = connector.connect(&self.client_config).await?;
+            self.reset_stream_state();
+            self.authenticate().await
+        }).await
+    }
+
     pub async fn authenticate(&mut self) -> Result<(), RconError> {
+        // Stays set if this fails or is cut short by a deadline, so the auth response is never read as a command's
+        self.needs_reconnect = true;
+        let span = trace::auth_span(&self.client_config);
+        trace::in_span(span, self.authenticate_in_span()).await?;
+        self.needs_reconnect = false;
+        Ok(())
+    }
+
+    async fn authenticate_in_span(&mut self) -> Result<(), RconError> {
         log::debug!("Starting authentication...");
-        let expected_id = self.write_packet(PacketType::ServerDataAuth, &self.client_config.password.clone()).await?;
+        let expected_id = self.write_packet(PacketType::ServerDataAuth, &self.client_config.password.clone().into_bytes()).await?;
+        trace::record("cmd_id", expected_id.into());
 
+        let mut packets = 0;
         loop {
-            let pkt: Packet = self.read_packet().await?;
-            if pkt.packet_type != PacketType::ServerDataAuthResponse && pkt.packet_type != PacketType::ServerDataExecCommand {
-                log::debug!("Received non-auth response packet while waiting for auth response, ignoring: {:?}", pkt);
-                continue;
-            }
-
-            if pkt.id == -1 {
-                return Err(RconError::AuthFailed);
-            }
-            if pkt.id != expected_id {
-                return Err(
-                    RconError::Protocol(format!("Auth packet id response mismatch. Expected: {:?}, got: {:?}", expected_id, pkt.id))
-                )
+            let pkt: Packet = self.read_packet(Phase::Auth).await?;
+            packets += 1;
+            trace::record("packets", packets);
+            match check_auth_response(&pkt, expected_id, self.client_config.lenient_ids) {
+                Ok(true) => return Ok(()),
+                Ok(false) if pkt.id != expected_id && !self.client_config.lenient_ids => self.publish(pkt),
+                Ok(false) => log::debug!("Received non-auth response packet with id {:?} while waiting for auth response, ignoring", pkt.id),
+                Err(e) => {
+                    if matches!(e, RconError::AuthFailed) {
+                        self.record(Event::AuthFailed);
+                    }
+                    return Err(e);
+                },
             }
-
-            return Ok(());
         }
     }
 }
@@ -45,7 +140,7 @@ impl<S: AsyncRead + AsyncWrite + Unpin> RconClient<S> {
 
 #[cfg(test)]
 mod tests {
-    use crate::RconClientConfig;
+    use crate::{RconClientConfig, dialect::Dialect};
 
     use super::*;
     use tokio::io::duplex;
@@ -64,13 +159,13 @@ mod tests {
         let server = tokio::spawn(async move {
             let mut server_client = RconClient::new(server_stream);
 
-            let pkt = server_client.read_packet().await.unwrap();
+            let pkt = server_client.read_request().await.unwrap();
             server_client = server_client.with_next_id(pkt.id);
 
             assert_eq!(pkt.packet_type, PacketType::ServerDataAuth);
-            assert_eq!(pkt.body, "pw");
+            assert_eq!(pkt.body, b"pw");
 
-            server_client.write_packet(PacketType::ServerDataAuthResponse, "").await.unwrap();
+            server_client.write_packet(PacketType::ServerDataAuthResponse, b"").await.unwrap();
         });
 
         client.authenticate().await.unwrap();
@@ -89,8 +184,8 @@ mod tests {
         let server = tokio::spawn(async move {
             let mut server_client = RconClient::new(server_stream)
                 .with_next_id(-1); // -1 is the id used by the server to indicate failed auth
-            let _req = server_client.read_packet().await.unwrap();
-            server_client.write_packet(PacketType::ServerDataAuthResponse, "").await.unwrap();
+            let _req = server_client.read_request().await.unwrap();
+            server_client.write_packet(PacketType::ServerDataAuthResponse, b"").await.unwrap();
         });
 
         let auth_result = client.authenticate().await;
@@ -99,6 +194,28 @@ mod tests {
         server.await.unwrap();
     }
 
+    #[tokio::test]
+    async fn auth_fails_on_minecraft_failed_login() {
+        let (client_stream, server_stream) = duplex(MAX_BUF_SIZE);
+        let mut client = RconClient::new(client_stream)
+            .with_client_config(RconClientConfig {
+                password: "pw".to_string(),
+                dialect: Dialect::Minecraft,
+                ..Default::default()
+            });
+
+        let server = tokio::spawn(async move {
+            let mut server_client = RconClient::new(server_stream)
+                .with_next_id(-1);
+            let _req = server_client.read_request().await.unwrap();
+            server_client.write_packet(PacketType::ServerDataAuthResponse, b"").await.unwrap();
+        });
+
+        let auth_result = client.authenticate().await;
+        assert!(matches!(auth_result, Err(RconError::AuthFailed)));
+        server.await.unwrap();
+    }
+
     #[tokio::test]
     async fn auth_ignores_unrelated_packets() {
         let (client_stream, server_stream) = duplex(MAX_BUF_SIZE);
@@ -106,14 +223,59 @@ mod tests {
 
         let server = tokio::spawn(async move {
             let mut server_client = RconClient::new(server_stream);
-            let req = server_client.read_packet().await.unwrap();
+            let req = server_client.read_request().await.unwrap();
             server_client = server_client.with_next_id(req.id);
-            server_client.write_packet(PacketType::ServerDataExecCommand, "unrelated").await.unwrap();
+            server_client.write_packet(PacketType::ServerDataResponseValue, b"unrelated").await.unwrap();
             server_client = server_client.with_next_id(req.id);
-            server_client.write_packet(PacketType::ServerDataAuthResponse, "").await.unwrap();
+            server_client.write_packet(PacketType::ServerDataAuthResponse, b"").await.unwrap();
         });
 
         client.authenticate().await.unwrap();
         server.await.unwrap();
     }
+
+    #[tokio::test]
+    async fn auth_with_mismatched_id_fails_unless_lenient() {
+        for lenient_ids in [false, true] {
+            let (client_stream, server_stream) = duplex(MAX_BUF_SIZE);
+            let mut client = RconClient::new(client_stream)
+                .with_client_config(RconClientConfig { lenient_ids, ..Default::default() });
+
+            let server = tokio::spawn(async move {
+                let mut server_client = RconClient::new(server_stream).with_next_id(0);
+                let _req = server_client.read_request().await.unwrap();
+                server_client.write_packet(PacketType::ServerDataAuthResponse, b"").await.unwrap();
+            });
+
+            let res = client.authenticate().await;
+            assert_eq!(res.is_ok(), lenient_ids);
+            server.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_with_uses_custom_connector() {
+        let connector = |_: &RconClientConfig| {
+            let (client_stream, server_stream) = duplex(MAX_BUF_SIZE);
+            tokio::spawn(async move {
+                let mut server_client = RconClient::new(server_stream);
+                let req = server_client.read_request().await.unwrap();
+                server_client = server_client.with_next_id(req.id);
+                server_client.write_packet(PacketType::ServerDataAuthResponse, b"").await.unwrap();
+            });
+            async move { Ok(client_stream) }
+        };
+
+        let client = RconClient::connect_with(RconClientConfig::default(), connector).await.unwrap();
+        assert!(client.connector.is_some());
+    }
+
+    #[tokio::test]
+    async fn reconnect_fails_without_connector() {
+        let (client_stream, _server_stream) = duplex(MAX_BUF_SIZE);
+        let mut client = RconClient::new(client_stream);
+
+        let res = client.reconnect().await;
+        assert!(matches!(res, Err(RconError::ClientError(_))));
+    }
 }
\ No newline at end of file
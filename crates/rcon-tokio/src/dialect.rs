@@ -0,0 +1,107 @@
+//! dialect.rs
+//!
+//! Games which implement RCON with different limits or framing than Valve's Source servers.
+//!
+//! Minecraft Java Edition:
+//! https://minecraft.wiki/w/RCON
+//!   - Commands may be up to 1446 bytes.
+//!   - Responses are split into fragments of 4096 characters, with no packet marking the end.
+//!     A fragment shorter than that is the last one.
+//!   - A failed login has id -1.
+
+use crate::errors::RconError;
+use crate::packet::{MAXIMUM_BODY_SIZE, MAXIMUM_PACKET_SIZE, MAXIMUM_PAYLOAD_SIZE};
+
+const MINECRAFT_MAXIMUM_COMMAND_SIZE: usize = 1446;
+const MINECRAFT_FRAGMENT_SIZE: usize = 4096;
+/// Fragments are split by character count, so a fragment's utf8 body may be up to 3 bytes per character.
+const MINECRAFT_MAXIMUM_PACKET_SIZE: usize = MINECRAFT_FRAGMENT_SIZE * 3 + 10;
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    #[default]
+    Source,
+    Minecraft,
+}
+
+impl Dialect {
+    /// The largest command body, in bytes, the server accepts in a single packet.
+    pub fn max_command_size(self) -> usize {
+        match self {
+            Dialect::Source => MAXIMUM_BODY_SIZE,
+            Dialect::Minecraft => MINECRAFT_MAXIMUM_COMMAND_SIZE,
+        }
+    }
+
+    /// Whether commands longer than `max_command_size` may be sent as several separate commands.
+    pub(crate) fn splits_long_commands(self) -> bool {
+        self == Dialect::Source
+    }
+
+    /// Splits a command into the bodies to send, one command each. Commands longer than `max_command_size`
+    /// are split where the server allows it, and rejected otherwise.
+    pub(crate) fn split_command(self, command: &[u8]) -> Result<Vec<&[u8]>, RconError> {
+        let max_body_size = self.max_command_size();
+        if command.len() <= max_body_size {
+            return Ok(vec![command]);
+        }
+        if !self.splits_long_commands() {
+            return Err(RconError::Protocol(
+                format!("Command exceeds {} bytes ({}) and cannot be split for {:?} servers", max_body_size, command.len(), self)
+            ));
+        }
+
+        log::warn!("Command exceeds {} bytes ({}), splitting into {} chunks",
+            max_body_size, command.len(), command.len().div_ceil(max_body_size));
+        Ok(command.chunks(max_body_size).collect())
+    }
+
+    /// The largest declared packet size accepted when reading.
+    pub(crate) fn max_packet_size(self) -> usize {
+        match self {
+            Dialect::Source => MAXIMUM_PACKET_SIZE,
+            Dialect::Minecraft => MINECRAFT_MAXIMUM_PACKET_SIZE,
+        }
+    }
+
+    /// The largest payload, excluding the size field, accepted when writing.
+    pub(crate) fn max_payload_size(self) -> usize {
+        match self {
+            Dialect::Source => MAXIMUM_PAYLOAD_SIZE,
+            Dialect::Minecraft => MINECRAFT_MAXIMUM_PACKET_SIZE,
+        }
+    }
+
+    /// Whether a response fragment is known to be the last one for its command.
+    pub(crate) fn is_final_fragment(self, body: &[u8]) -> bool {
+        match self {
+            Dialect::Source => false,
+            Dialect::Minecraft => String::from_utf8_lossy(body).encode_utf16().count() < MINECRAFT_FRAGMENT_SIZE,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn minecraft_fragments_are_final_when_shorter_than_fragment_size() {
+        assert!(Dialect::Minecraft.is_final_fragment(b""));
+        assert!(Dialect::Minecraft.is_final_fragment("é".repeat(MINECRAFT_FRAGMENT_SIZE - 1).as_bytes()));
+        assert!(!Dialect::Minecraft.is_final_fragment("é".repeat(MINECRAFT_FRAGMENT_SIZE).as_bytes()));
+    }
+
+    #[test]
+    fn split_command_splits_long_source_commands_only() {
+        assert_eq!(Dialect::Source.split_command(b"").unwrap(), vec![b""]);
+        assert_eq!(Dialect::Source.split_command(&[b'a'; 600]).unwrap().iter().map(|c| c.len()).collect::<Vec<_>>(), [511, 89]);
+        assert!(Dialect::Minecraft.split_command(&[b'a'; 1447]).is_err());
+    }
+
+    #[test]
+    fn source_fragments_are_never_known_final() {
+        assert!(!Dialect::Source.is_final_fragment(b""));
+        assert!(!Dialect::Source.is_final_fragment(b"short"));
+    }
+}
@@ -0,0 +1,69 @@
+//! encoding.rs
+//!
+//! How `execute` turns the raw bytes of a response into a `String`.
+//!
+//! The protocol does not specify an encoding. Most servers send UTF-8, but some send player names
+//! or colour codes as Latin-1 or Windows-1252 bytes, which are not valid UTF-8.
+
+use crate::errors::RconError;
+
+/// Windows-1252 characters for bytes 0x80..=0x9F. Bytes the code page leaves undefined map to
+/// the C1 control character of the same value, as Latin-1 does.
+const WINDOWS_1252_HIGH: [char; 32] = [
+    '\u{20AC}', '\u{0081}', '\u{201A}', '\u{0192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{02C6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', '\u{008D}', '\u{017D}', '\u{008F}',
+    '\u{0090}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{02DC}', '\u{2122}', '\u{0161}', '\u{203A}', '\u{0153}', '\u{009D}', '\u{017E}', '\u{0178}',
+];
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseEncoding {
+    /// Responses which are not valid UTF-8 fail with `RconError::Utf8`.
+    #[default]
+    Utf8,
+    /// Invalid UTF-8 sequences are replaced with U+FFFD.
+    Utf8Lossy,
+    /// Every byte is the Unicode code point of the same value.
+    Latin1,
+    /// Latin-1, with the typographic characters Windows places at 0x80..=0x9F.
+    Windows1252,
+}
+
+impl ResponseEncoding {
+    pub fn decode(self, bytes: Vec<u8>) -> Result<String, RconError> {
+        match self {
+            ResponseEncoding::Utf8 => Ok(String::from_utf8(bytes)?),
+            ResponseEncoding::Utf8Lossy => Ok(String::from_utf8_lossy(&bytes).into_owned()),
+            ResponseEncoding::Latin1 => Ok(bytes.iter().map(|&b| b as char).collect()),
+            ResponseEncoding::Windows1252 => Ok(bytes
+                .iter()
+                .map(|&b| match b {
+                    0x80..=0x9F => WINDOWS_1252_HIGH[(b - 0x80) as usize],
+                    b => b as char,
+                })
+                .collect()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utf8_rejects_invalid_bytes_and_lossy_replaces_them() {
+        let bytes = b"caf\xe9".to_vec();
+
+        assert!(matches!(ResponseEncoding::Utf8.decode(bytes.clone()), Err(RconError::Utf8(_))));
+        assert_eq!(ResponseEncoding::Utf8Lossy.decode(bytes).unwrap(), "caf\u{FFFD}");
+        assert_eq!(ResponseEncoding::Utf8.decode("café".as_bytes().to_vec()).unwrap(), "café");
+    }
+
+    #[test]
+    fn latin1_and_windows1252_differ_only_in_high_control_range() {
+        let bytes = b"caf\xe9 \x80\x93\x81".to_vec();
+
+        assert_eq!(ResponseEncoding::Latin1.decode(bytes.clone()).unwrap(), "café \u{80}\u{93}\u{81}");
+        assert_eq!(ResponseEncoding::Windows1252.decode(bytes).unwrap(), "café €“\u{81}");
+    }
+}
@@ -28,3 +28,22 @@ impl From<std::io::Error> for RconError {
 impl From<std::string::FromUtf8Error> for RconError {
     fn from(e: std::string::FromUtf8Error) -> Self { RconError::Utf8(e) }
 }
+
+
+/// A failed attempt to send a command, and whether any of it may have reached the server.
+/// Commands which never reached the server are always safe to send again.
+#[derive(Debug)]
+pub(crate) struct SendError {
+    pub(crate) error: RconError,
+    pub(crate) reached_server: bool,
+}
+
+impl SendError {
+    pub(crate) fn unsent(error: RconError) -> Self {
+        SendError { error, reached_server: false }
+    }
+}
+
+impl From<SendError> for RconError {
+    fn from(e: SendError) -> Self { e.error }
+}
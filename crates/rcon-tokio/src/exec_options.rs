@@ -0,0 +1,77 @@
+//! exec_options.rs
+//!
+//! Per call overrides for `RconClient::execute_with`, so one slow command, eg: `cvarlist` or a Minecraft
+//! world save, can wait longer than the rest without rebuilding the client.
+//!
+//! Unset options fall back to the client's `RconClientConfig`. Reconnects made while retrying the command
+//! still authenticate with the config's `io_timeout`.
+
+use std::time::{Duration, Instant};
+
+use crate::client_config::{RconClientConfig, ResponseFraming};
+use crate::retry::RetryPolicy;
+
+#[derive(Debug, Default, Clone)]
+pub struct ExecOptions {
+    pub io_timeout: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+    pub response_framing: Option<ResponseFraming>,
+    pub auto_reconnect: Option<bool>,
+    pub max_reconnect_attempts: Option<usize>,
+    pub retry_policy: Option<RetryPolicy>,
+    pub deadline: Option<Instant>,
+}
+
+impl ExecOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How long to wait on each packet of the command and its response.
+    pub fn io_timeout(mut self, t: Duration) -> Self { self.io_timeout = Some(t); self }
+
+    /// How long to wait for more response packets with `ResponseFraming::IdleTimeout`.
+    pub fn idle_timeout(mut self, t: Duration) -> Self { self.idle_timeout = Some(t); self }
+
+    /// How the end of this command's response is detected.
+    pub fn response_framing(mut self, f: ResponseFraming) -> Self { self.response_framing = Some(f); self }
+
+    /// Whether to reconnect and retry this command if it fails.
+    pub fn auto_reconnect(mut self, v: bool) -> Self { self.auto_reconnect = Some(v); self }
+
+    /// The maximum number of attempts at this command.
+    pub fn max_reconnect_attempts(mut self, v: usize) -> Self { self.max_reconnect_attempts = Some(v); self }
+
+    /// The backoff between attempts at this command, and whether it is safe to send again.
+    pub fn retry_policy(mut self, v: RetryPolicy) -> Self { self.retry_policy = Some(v); self }
+
+    /// Fails the call with `RconError::Timeout` at `deadline`, including any retries, reconnects and rate limiting.
+    pub fn deadline(mut self, deadline: Instant) -> Self { self.deadline = Some(deadline); self }
+
+    /// Like `deadline`, `t` from now.
+    pub fn timeout(self, t: Duration) -> Self { self.deadline(Instant::now() + t) }
+
+    pub(crate) fn io_timeout_or(&self, config: &RconClientConfig) -> Duration {
+        self.io_timeout.unwrap_or(config.io_timeout)
+    }
+
+    pub(crate) fn idle_timeout_or(&self, config: &RconClientConfig) -> Duration {
+        self.idle_timeout.unwrap_or(config.idle_timeout)
+    }
+
+    pub(crate) fn response_framing_or(&self, config: &RconClientConfig) -> ResponseFraming {
+        self.response_framing.unwrap_or(config.response_framing)
+    }
+
+    pub(crate) fn auto_reconnect_or(&self, config: &RconClientConfig) -> bool {
+        self.auto_reconnect.unwrap_or(config.auto_reconnect)
+    }
+
+    pub(crate) fn max_reconnect_attempts_or(&self, config: &RconClientConfig) -> usize {
+        self.max_reconnect_attempts.unwrap_or(config.max_reconnect_attempts)
+    }
+
+    pub(crate) fn retry_policy_or<'a>(&'a self, config: &'a RconClientConfig) -> &'a RetryPolicy {
+        self.retry_policy.as_ref().unwrap_or(&config.retry_policy)
+    }
+}
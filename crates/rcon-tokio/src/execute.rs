@@ -1,108 +1,243 @@
-use tokio::{io::{AsyncRead, AsyncWrite}, net::TcpStream, time::timeout};
+use std::time::Instant;
 
-use crate::{client::RconClient, common::PacketType, errors::RconError};
+use tokio::{io::{AsyncRead, AsyncWrite}, time::{timeout, timeout_at}};
 
+use crate::{client::RconClient, client_config::ResponseFraming, common::{PacketType, Phase}, errors::RconError, packet::Packet, response::ResponseAssembler};
+use crate::errors::SendError;
+use crate::exec_options::ExecOptions;
+use crate::retry::{Attempt, Attempts};
+use crate::stats::Event;
+use crate::trace;
 
-impl RconClient<TcpStream> {
+
+impl<S: AsyncRead + AsyncWrite + Unpin> RconClient<S> {
+    /// Executes a command, decoding the response with the configured `ResponseEncoding`.
     pub async fn execute(&mut self, command: &str) -> Result<String, RconError> {
-        const MAX_BODY_SIZE: usize = 511;
-        
-        if command.len() <= MAX_BODY_SIZE {
-            return self.execute_with_retry(command).await;
-        }
+        self.execute_with(command, ExecOptions::default()).await
+    }
 
-        log::warn!("Command exceeds {} bytes ({}), splitting into {} chunks", 
-            MAX_BODY_SIZE, command.len(), (command.len() + MAX_BODY_SIZE - 1) / MAX_BODY_SIZE);
+    /// Like `execute`, overriding the config's timeouts, framing and retries for this call only.
+    pub async fn execute_with(&mut self, command: &str, options: ExecOptions) -> Result<String, RconError> {
+        let response = self.execute_bytes_with(command.as_bytes(), options).await?;
+        self.client_config.response_encoding.decode(response)
+    }
 
-        let chunks: Vec<&str> = command
-            .as_bytes()
-            .chunks(MAX_BODY_SIZE)
-            .map(|chunk| std::str::from_utf8(chunk).unwrap_or(""))
-            .collect();
+    /// Executes a command, returning the raw bytes of the response.
+    pub async fn execute_bytes(&mut self, command: &[u8]) -> Result<Vec<u8>, RconError> {
+        self.execute_bytes_with(command, ExecOptions::default()).await
+    }
 
+    /// Like `execute_bytes`, overriding the config's timeouts, framing and retries for this call only.
+    pub async fn execute_bytes_with(&mut self, command: &[u8], options: ExecOptions) -> Result<Vec<u8>, RconError> {
         let mut results = Vec::new();
-        for chunk in chunks {
-            results.push(self.execute_with_retry(chunk).await?);
+        for chunk in self.client_config.dialect.split_command(command)? {
+            results.extend(self.execute_with_retry(chunk, &options).await?);
         }
-        Ok(results.join(""))
+        Ok(results)
     }
 
-    async fn execute_with_retry(&mut self, command: &str) -> Result<String, RconError> {
-        for attempt in 0..self.client_config.max_reconnect_attempts {
-            log::debug!("Executing command with attempt {}/{}", attempt + 1, self.client_config.max_reconnect_attempts);
-            match self._execute(command).await {
-                Ok(result) => return Ok(result),
-                Err(e) => log::warn!("Failed to execute command on attempt {}/{}. Error: {:?}", attempt + 1, self.client_config.max_reconnect_attempts, e),
-            }
+    async fn execute_with_retry(&mut self, command: &[u8], options: &ExecOptions) -> Result<Vec<u8>, RconError> {
+        let start = Instant::now();
+        let span = trace::execute_span(&self.client_config);
+        let res = trace::in_span(span, self.execute_before_deadline(command, options)).await;
+        self.record(Event::Command { latency: start.elapsed(), ok: res.is_ok() });
+        res
+    }
 
-            if self.client_config.auto_reconnect && attempt < self.client_config.max_reconnect_attempts {
-                log::warn!("Attempting to reconnect client and retry command execution");
-                *self = RconClient::connect(self.client_config.clone()).await?;
-            } else {
-                break;
-            }
-        }
+    async fn execute_before_deadline(&mut self, command: &[u8], options: &ExecOptions) -> Result<Vec<u8>, RconError> {
+        let Some(deadline) = options.deadline else {
+            return self.execute_attempts(command, options).await;
+        };
 
-        Err(RconError::ClientError(format!("Failed to execute command after {} attempts", self.client_config.max_reconnect_attempts)))
+        match timeout_at(deadline.into(), self.execute_attempts(command, options)).await {
+            Ok(res) => res,
+            Err(_) => {
+                log::warn!("Command deadline passed, giving up");
+                self.record(Event::Timeout(Phase::Command));
+                Err(RconError::Timeout)
+            },
+        }
     }
-}
-
-impl<S: AsyncRead + AsyncWrite + Unpin> RconClient<S> {
-    async fn _execute(&mut self, command: &str) -> Result<String, RconError> {
-        log::debug!("Executing command: {:?}", command);
-        let cmd_id = self.write_packet(PacketType::ServerDataExecCommand, command).await?;
 
-        let mut out = String::new();
-        let mut data_seen = false;
+    async fn execute_attempts(&mut self, command: &[u8], options: &ExecOptions) -> Result<Vec<u8>, RconError> {
+        let policy = options.retry_policy_or(&self.client_config).clone();
+        let mut attempts = Attempts::new(
+            &policy,
+            options.auto_reconnect_or(&self.client_config),
+            options.max_reconnect_attempts_or(&self.client_config),
+            command,
+        );
 
         loop {
-            match timeout(self.client_config.idle_timeout, self.read_packet()).await {
-                Ok(Ok(pkt)) => {
-                    if pkt.id != cmd_id {
-                        log::debug!("Received packet with id {:?} while waiting for response to command with id {:?}, ignoring", pkt.id, cmd_id);
+            match attempts.next() {
+                Attempt::Send => {},
+                Attempt::Reconnect(backoff) => {
+                    tokio::time::sleep(backoff).await;
+                    if let Err(e) = self.reconnect().await {
+                        attempts.reconnect_failed(e);
                         continue;
                     }
-
-                    data_seen = true;
-                    let ptype: i32 = pkt.packet_type.into();
-                    match ptype {
-                        0 => out.push_str(&pkt.body),
-                        2 => out.push_str(&pkt.body),
-                        _ => log::debug!(
-                            "Received packet with unexpected type {:?} while waiting for command response, ignoring", 
-                            pkt.packet_type
-                        )
-                    }
                 },
-                Ok(Err(e)) => {
-                    return Err(e)
+                Attempt::GiveUp(e) => return Err(e),
+            }
+
+            trace::record("attempt", attempts.number() as i64);
+            self.throttle(command).await?;
+            let (cmd_id, mirror_id) = match self.send_command(command, options).await {
+                Ok(ids) => ids,
+                Err(e) => {
+                    attempts.send_failed(e)?;
+                    continue;
                 },
-                Err(_) => {
-                    if data_seen {
-                        log::debug!("Idle timeout reached while waiting for more data, returning response");
+            };
+
+            match self.read_response(cmd_id, mirror_id, options).await {
+                Ok(result) => return Ok(result),
+                Err(e) => attempts.response_failed(e)?,
+            }
+        }
+    }
+
+    pub(crate) async fn _execute(&mut self, command: &str) -> Result<String, RconError> {
+        let options = ExecOptions::default();
+        let (cmd_id, mirror_id) = self.send_command(command.as_bytes(), &options).await?;
+        let response = self.read_response(cmd_id, mirror_id, &options).await?;
+        self.client_config.response_encoding.decode(response)
+    }
+
+    /// Writes the command, followed by the empty packet the server mirrors back when using `ResponseFraming::Mirror`.
+    /// The command stays in flight until `read_response` has read its whole response.
+    /// 
+    /// ### Returns
+    /// - The id of the command packet, and of the mirror packet if one was sent.
+    /// - Or the error, and whether any of the command was written before it happened.
+    pub(crate) async fn send_command(&mut self, command: &[u8], options: &ExecOptions) -> Result<(i32, Option<i32>), SendError> {
+        self.resync(options).await.map_err(SendError::unsent)?;
+
+        let cmd_id = self.queue_packet(PacketType::ServerDataExecCommand, command).map_err(SendError::unsent)?;
+        trace::command_sent(cmd_id, command.len());
+        let mirror_id = match options.response_framing_or(&self.client_config) {
+            ResponseFraming::Mirror => Some(self.queue_packet(PacketType::ServerDataResponseValue, b"").map_err(SendError::unsent)?),
+            _ => None,
+        };
+        self.in_flight = Some((cmd_id, mirror_id));
+
+        // Resyncing left the buffer empty, so it now holds just the command
+        let queued = self.write_buf.len();
+        if let Err(error) = self.flush_packets(options.io_timeout_or(&self.client_config), Phase::Command).await {
+            return Err(SendError { error, reached_server: self.write_buf.len() < queued });
+        }
+
+        trace::record("cmd_id", cmd_id.into());
+        Ok((cmd_id, mirror_id))
+    }
+
+    /// Clears out whatever a cancelled or failed command left behind, so it cannot be mistaken for the next response.
+    /// A partly written packet is finished and unsent ones are dropped. Then, if the command was sent, its response
+    /// is read and discarded until its mirror packet comes back, or the server goes quiet for `idle_timeout`.
+    async fn resync(&mut self, options: &ExecOptions) -> Result<(), RconError> {
+        if self.needs_reconnect {
+            return Err(RconError::ClientError("Connection is not usable until the client reconnects".to_string()));
+        }
+        let io_timeout = options.io_timeout_or(&self.client_config);
+        let dropped = self.finish_partial_write(io_timeout, Phase::Command).await?;
+        let Some((cmd_id, mirror_id)) = self.in_flight else {
+            return Ok(());
+        };
+        if dropped.contains(&cmd_id) {
+            log::debug!("Command with id {:?} was never sent, nothing to discard", cmd_id);
+            self.in_flight = None;
+            return Ok(());
+        }
+        // Without its mirror packet, the response can only end on `idle_timeout`
+        let mirror_id = mirror_id.filter(|id| !dropped.contains(id));
+        self.in_flight = Some((cmd_id, mirror_id));
+
+        log::debug!("Discarding the response to abandoned command with id {:?}", cmd_id);
+        let idle_timeout = options.idle_timeout_or(&self.client_config);
+        let res = loop {
+            let pkt = match timeout(idle_timeout, self.read_packet_within(Phase::Command, io_timeout)).await {
+                Ok(Ok(pkt)) => pkt,
+                Ok(Err(e)) => break Err(e),
+                Err(_) => break Ok(()),
+            };
+
+            let mirrored = mirror_id == Some(pkt.id);
+            if pkt.id != cmd_id && !mirrored && !self.client_config.lenient_ids {
+                self.publish_unless_stale(pkt);
+            }
+            if mirrored {
+                break Ok(());
+            }
+        };
+
+        // Only once drained, so a resync which is itself cancelled runs again before the next command
+        self.abandon_in_flight();
+        res
+    }
+
+    /// Publishes a packet which matches no pending command, unless it belongs to an abandoned one.
+    fn publish_unless_stale(&mut self, pkt: Packet) {
+        if self.stale_ids.contains(&pkt.id) {
+            log::debug!("Discarding packet with id {:?} of an abandoned command", pkt.id);
+            return;
+        }
+        self.publish(pkt);
+    }
+
+    pub(crate) async fn read_response(&mut self, cmd_id: i32, mirror_id: Option<i32>, options: &ExecOptions) -> Result<Vec<u8>, RconError> {
+        let framing = options.response_framing_or(&self.client_config);
+        let io_timeout = options.io_timeout_or(&self.client_config);
+        let idle_timeout = options.idle_timeout_or(&self.client_config);
+        let mut response = ResponseAssembler::new(framing, self.client_config.dialect, cmd_id, mirror_id)
+            .lenient_ids(self.client_config.lenient_ids);
+
+        let mut packets = 0;
+        loop {
+            let pkt = if framing == ResponseFraming::IdleTimeout {
+                match timeout(idle_timeout, self.read_packet_within(Phase::Command, io_timeout)).await {
+                    Ok(res) => res?,
+                    Err(_) => {
+                        response.idle();
                         break;
                     }
-
-                    log::debug!("Idle timeout reached without receiving any data, returning empty response");
-                    break;
                 }
+            } else {
+                self.read_packet_within(Phase::Command, io_timeout).await?
+            };
+
+            if response.is_unmatched(&pkt) {
+                self.publish_unless_stale(pkt);
+                continue;
+            }
+            packets += 1;
+            if response.push(pkt) {
+                break;
             }
         }
 
-        Ok(out)
+        trace::record("packets", packets);
+        self.in_flight = None;
+        Ok(response.finish())
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::time::Duration;
 
+    use crate::connect::Connector;
+    use crate::dialect::Dialect;
+    use crate::encoding::ResponseEncoding;
+    use crate::retry::RetryPolicy;
+    use crate::server::{CommandHandler, RconServer};
     use crate::RconClientConfig;
 
     use super::*;
-    use tokio::io::duplex;
-
-    const MAX_BUFFER_SIZE: usize = 4096;
+    use crate::test_support::{self, echo_connector, serve_connector, Echo, MAX_BUFFER_SIZE};
+    use tokio::io::{duplex, DuplexStream};
 
     #[tokio::test]
     async fn exec_aggregates_multiple_packets_then_stops_on_idle() {
@@ -124,14 +259,14 @@ mod tests {
                     ..Default::default()
                 });
             
-            let cmd = server_client.read_packet().await.unwrap();
+            let cmd = server_client.read_request().await.unwrap();
             assert_eq!(cmd.packet_type, PacketType::ServerDataExecCommand);
-            assert_eq!(cmd.body, "cmd");
+            assert_eq!(cmd.body, b"cmd");
 
             server_client = server_client.with_next_id(EXPECTED_ID);
-            server_client.write_packet(PacketType::ServerDataExecCommand, "hello ").await.unwrap();
+            server_client.write_packet(PacketType::ServerDataExecCommand, b"hello ").await.unwrap();
             server_client = server_client.with_next_id(EXPECTED_ID);
-            server_client.write_packet(PacketType::ServerDataExecCommand, "world").await.unwrap();
+            server_client.write_packet(PacketType::ServerDataExecCommand, b"world").await.unwrap();
             tokio::time::sleep(TIMEOUT * 2).await;
         });
 
@@ -161,14 +296,14 @@ mod tests {
                     ..Default::default()
                 });
             
-            let cmd = server_client.read_packet().await.unwrap();
+            let cmd = server_client.read_request().await.unwrap();
             assert_eq!(cmd.packet_type, PacketType::ServerDataExecCommand);
-            assert_eq!(cmd.body, "cmd");
+            assert_eq!(cmd.body, b"cmd");
 
             server_client = server_client.with_next_id(UNRELATED_ID);
-            server_client.write_packet(PacketType::ServerDataExecCommand, "unrelated").await.unwrap();
+            server_client.write_packet(PacketType::ServerDataExecCommand, b"unrelated").await.unwrap();
             server_client = server_client.with_next_id(EXPECTED_ID);
-            server_client.write_packet(PacketType::ServerDataExecCommand, "hello world").await.unwrap();
+            server_client.write_packet(PacketType::ServerDataExecCommand, b"hello world").await.unwrap();
             tokio::time::sleep(TIMEOUT * 2).await;
         });
 
@@ -176,4 +311,468 @@ mod tests {
         assert_eq!(out, "hello world");
         server.await.unwrap();
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn exec_publishes_unrelated_packets_to_subscribers() {
+        const TIMEOUT: Duration = Duration::from_millis(100);
+        const EVENT_ID: i32 = 99;
+
+        let (client_stream, server_stream) = duplex(MAX_BUFFER_SIZE);
+        let mut client = RconClient::new(client_stream)
+            .with_client_config(RconClientConfig {
+                idle_timeout: TIMEOUT,
+                io_timeout: Duration::from_secs(1),
+                ..Default::default()
+            });
+        let mut events = client.subscribe();
+
+        let server = tokio::spawn(async move {
+            let mut server_client = RconClient::new(server_stream)
+                .with_client_config(RconClientConfig {
+                    io_timeout: Duration::from_secs(1),
+                    ..Default::default()
+                });
+
+            let cmd = server_client.read_request().await.unwrap();
+            server_client = server_client.with_next_id(EVENT_ID);
+            server_client.write_packet(PacketType::ServerDataResponseValue, b"[Chat] player: hi").await.unwrap();
+            server_client = server_client.with_next_id(cmd.id);
+            server_client.write_packet(PacketType::ServerDataResponseValue, b"hello world").await.unwrap();
+            tokio::time::sleep(TIMEOUT * 2).await;
+        });
+
+        assert_eq!(client._execute("cmd").await.unwrap(), "hello world");
+        let event = events.try_recv().unwrap();
+        assert_eq!((event.id, event.packet_type), (EVENT_ID, PacketType::ServerDataResponseValue));
+        assert_eq!(event.body, b"[Chat] player: hi");
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn exec_with_lenient_ids_accepts_id_zero_responses() {
+        let (client_stream, server_stream) = duplex(MAX_BUFFER_SIZE);
+        let mut client = RconClient::new(client_stream)
+            .with_client_config(RconClientConfig {
+                idle_timeout: Duration::from_millis(50),
+                io_timeout: Duration::from_secs(1),
+                lenient_ids: true,
+                ..Default::default()
+            });
+
+        let server = tokio::spawn(async move {
+            let mut server_client = RconClient::new(server_stream)
+                .with_client_config(RconClientConfig {
+                    io_timeout: Duration::from_secs(1),
+                    ..Default::default()
+                });
+
+            let _cmd = server_client.read_request().await.unwrap();
+            server_client = server_client.with_next_id(0);
+            server_client.write_packet(PacketType::ServerDataResponseValue, b"hello").await.unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        });
+
+        let out = client._execute("cmd").await.unwrap();
+        assert_eq!(out, "hello");
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn execute_decodes_non_utf8_responses_with_configured_encoding() {
+        for (encoding, expected) in [(ResponseEncoding::Utf8, None), (ResponseEncoding::Latin1, Some("café"))] {
+            let (client_stream, server_stream) = duplex(MAX_BUFFER_SIZE);
+            let mut client = RconClient::new(client_stream)
+                .with_client_config(RconClientConfig {
+                    idle_timeout: Duration::from_millis(50),
+                    io_timeout: Duration::from_secs(1),
+                    max_reconnect_attempts: 1,
+                    response_encoding: encoding,
+                    ..Default::default()
+                });
+
+            let server = tokio::spawn(async move {
+                let mut server_client = RconClient::new(server_stream)
+                    .with_client_config(RconClientConfig {
+                        io_timeout: Duration::from_secs(1),
+                        ..Default::default()
+                    });
+
+                for _ in 0..2 {
+                    let cmd = server_client.read_request().await.unwrap();
+                    server_client = server_client.with_next_id(cmd.id);
+                    server_client.write_packet(PacketType::ServerDataResponseValue, b"caf\xe9").await.unwrap();
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            });
+
+            assert_eq!(client.execute_bytes(b"cmd").await.unwrap(), b"caf\xe9");
+            assert_eq!(client.execute("cmd").await.ok().as_deref(), expected);
+            server.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn exec_with_mirror_framing_stops_on_mirrored_packet() {
+        const EXPECTED_ID: i32 = 1;
+
+        let (client_stream, server_stream) = duplex(MAX_BUFFER_SIZE);
+        let mut client = RconClient::new(client_stream)
+            .with_client_config(RconClientConfig {
+                io_timeout: Duration::from_secs(1),
+                response_framing: ResponseFraming::Mirror,
+                ..Default::default()
+            });
+
+        let server = tokio::spawn(async move {
+            let mut server_client = RconClient::new(server_stream)
+                .with_client_config(RconClientConfig {
+                    io_timeout: Duration::from_secs(1),
+                    ..Default::default()
+                });
+
+            let cmd = server_client.read_request().await.unwrap();
+            assert_eq!(cmd.body, b"cmd");
+            let mirror = server_client.read_request().await.unwrap();
+            assert_eq!(mirror.packet_type, PacketType::ServerDataResponseValue);
+            assert_eq!(mirror.body, b"");
+
+            server_client = server_client.with_next_id(EXPECTED_ID);
+            server_client.write_packet(PacketType::ServerDataResponseValue, b"hello ").await.unwrap();
+            server_client = server_client.with_next_id(EXPECTED_ID);
+            server_client.write_packet(PacketType::ServerDataResponseValue, b"world").await.unwrap();
+            server_client = server_client.with_next_id(mirror.id);
+            server_client.write_packet(PacketType::ServerDataResponseValue, b"").await.unwrap();
+        });
+
+        let out = client._execute("cmd").await.unwrap();
+        assert_eq!(out, "hello world");
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn exec_with_empty_packet_framing_stops_on_empty_packet() {
+        const EXPECTED_ID: i32 = 1;
+
+        let (client_stream, server_stream) = duplex(MAX_BUFFER_SIZE);
+        let mut client = RconClient::new(client_stream)
+            .with_client_config(RconClientConfig {
+                io_timeout: Duration::from_secs(1),
+                response_framing: ResponseFraming::EmptyPacket,
+                ..Default::default()
+            });
+
+        let server = tokio::spawn(async move {
+            let mut server_client = RconClient::new(server_stream)
+                .with_client_config(RconClientConfig {
+                    io_timeout: Duration::from_secs(1),
+                    ..Default::default()
+                });
+
+            let _cmd = server_client.read_request().await.unwrap();
+            server_client = server_client.with_next_id(EXPECTED_ID);
+            server_client.write_packet(PacketType::ServerDataResponseValue, b"hello").await.unwrap();
+            server_client = server_client.with_next_id(EXPECTED_ID);
+            server_client.write_packet(PacketType::ServerDataResponseValue, b"").await.unwrap();
+        });
+
+        let out = client._execute("cmd").await.unwrap();
+        assert_eq!(out, "hello");
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn exec_reassembles_minecraft_fragments_without_waiting_for_idle() {
+        const EXPECTED_ID: i32 = 1;
+
+        let (client_stream, server_stream) = duplex(MAX_BUFFER_SIZE * 4);
+        let mut client = RconClient::new(client_stream)
+            .with_client_config(RconClientConfig {
+                idle_timeout: Duration::from_secs(10),
+                io_timeout: Duration::from_secs(1),
+                max_reconnect_attempts: 1,
+                dialect: Dialect::Minecraft,
+                ..Default::default()
+            });
+
+        let server = tokio::spawn(async move {
+            let mut server_client = RconClient::new(server_stream)
+                .with_client_config(RconClientConfig {
+                    io_timeout: Duration::from_secs(1),
+                    dialect: Dialect::Minecraft,
+                    ..Default::default()
+                });
+
+            let cmd = server_client.read_request().await.unwrap();
+            assert_eq!(cmd.body.len(), 1000);
+
+            server_client = server_client.with_next_id(EXPECTED_ID);
+            server_client.write_packet(PacketType::ServerDataResponseValue, "a".repeat(4096).as_bytes()).await.unwrap();
+            server_client = server_client.with_next_id(EXPECTED_ID);
+            server_client.write_packet(PacketType::ServerDataResponseValue, b"end").await.unwrap();
+        });
+
+        let out = timeout(Duration::from_secs(1), client.execute(&"c".repeat(1000))).await.unwrap().unwrap();
+        assert_eq!(out, "a".repeat(4096) + "end");
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn execute_rejects_minecraft_commands_over_limit() {
+        let (client_stream, _server_stream) = duplex(MAX_BUFFER_SIZE);
+        let mut client = RconClient::new(client_stream)
+            .with_client_config(RconClientConfig {
+                dialect: Dialect::Minecraft,
+                ..Default::default()
+            });
+
+        let res = client.execute(&"a".repeat(1447)).await;
+        assert!(matches!(res, Err(RconError::Protocol(_))));
+    }
+
+    #[tokio::test]
+    async fn execute_reconnects_through_connector() {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let config = test_support::config().auto_reconnect(true);
+        let mut client = RconClient::connect_with(config, echo_connector(Arc::clone(&connects))).await.unwrap();
+
+        // Swap in a stream whose peer is gone, as if the connection had died
+        let (dead_stream, _) = duplex(MAX_BUFFER_SIZE);
+        client.stream = dead_stream;
+
+        assert_eq!(client.execute("cmd").await.unwrap(), "cmd");
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+    }
+
+    /// The first connection authenticates, then swallows every command without replying.
+    /// Later connections echo commands back.
+    fn silent_then_echo_connector(connects: Arc<AtomicUsize>) -> impl Connector<DuplexStream> {
+        move |_: &RconClientConfig| {
+            let (client_stream, server_stream) = duplex(MAX_BUFFER_SIZE);
+            if connects.fetch_add(1, Ordering::SeqCst) > 0 {
+                tokio::spawn(async move { RconServer::new("pw".to_string(), Echo).serve(server_stream).await });
+            } else {
+                tokio::spawn(serve_silently(server_stream));
+            }
+            async move { Ok(client_stream) }
+        }
+    }
+
+    /// Every connection authenticates, then swallows commands.
+    fn silent_connector(connects: Arc<AtomicUsize>) -> impl Connector<DuplexStream> {
+        move |_: &RconClientConfig| {
+            connects.fetch_add(1, Ordering::SeqCst);
+            let (client_stream, server_stream) = duplex(MAX_BUFFER_SIZE);
+            tokio::spawn(serve_silently(server_stream));
+            async move { Ok(client_stream) }
+        }
+    }
+
+    async fn serve_silently(server_stream: DuplexStream) {
+        let mut server_client = RconClient::new(server_stream)
+            .with_client_config(RconClientConfig {
+                io_timeout: Duration::from_secs(10),
+                ..Default::default()
+            });
+        let req = server_client.read_request().await.unwrap();
+        server_client = server_client.with_next_id(req.id);
+        server_client.write_packet(PacketType::ServerDataAuthResponse, b"").await.unwrap();
+        while server_client.read_request().await.is_ok() {}
+    }
+
+    fn retrying_config(policy: RetryPolicy) -> RconClientConfig {
+        test_support::config()
+            .io_timeout(Duration::from_millis(50))
+            .response_framing(ResponseFraming::Mirror)
+            .auto_reconnect(true)
+            .retry_policy(policy.initial_backoff(Duration::from_millis(1)))
+    }
+
+    #[tokio::test]
+    async fn execute_retries_idempotent_commands_after_send() {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let config = retrying_config(RetryPolicy::new());
+        let mut client = RconClient::connect_with(config, silent_then_echo_connector(Arc::clone(&connects))).await.unwrap();
+
+        assert_eq!(client.execute("status").await.unwrap(), "status");
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn execute_surfaces_timeout_for_non_idempotent_commands() {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let config = retrying_config(RetryPolicy::new().non_idempotent_prefix("ban"));
+        let mut client = RconClient::connect_with(config, silent_then_echo_connector(Arc::clone(&connects))).await.unwrap();
+
+        let res = client.execute("ban griefer").await;
+
+        assert!(matches!(res, Err(RconError::Timeout)));
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+    }
+
+    /// The first connection authenticates, then stops reading, so only part of a command can be written.
+    /// Later connections echo commands back.
+    fn stalled_then_echo_connector(connects: Arc<AtomicUsize>) -> impl Connector<DuplexStream> {
+        move |_: &RconClientConfig| {
+            let first = connects.fetch_add(1, Ordering::SeqCst) == 0;
+            let (client_stream, server_stream) = duplex(if first { 32 } else { MAX_BUFFER_SIZE });
+            if first {
+                tokio::spawn(async move {
+                    let mut server_client = RconClient::new(server_stream);
+                    let req = server_client.read_request().await.unwrap();
+                    server_client = server_client.with_next_id(req.id);
+                    server_client.write_packet(PacketType::ServerDataAuthResponse, b"").await.unwrap();
+                    tokio::time::sleep(Duration::from_secs(10)).await;
+                });
+            } else {
+                tokio::spawn(async move { RconServer::new("pw".to_string(), Echo).serve(server_stream).await });
+            }
+            async move { Ok(client_stream) }
+        }
+    }
+
+    #[tokio::test]
+    async fn execute_does_not_resend_non_idempotent_commands_after_partial_send() {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let config = retrying_config(RetryPolicy::new().non_idempotent_prefix("ban"));
+        let mut client = RconClient::connect_with(config, stalled_then_echo_connector(Arc::clone(&connects))).await.unwrap();
+
+        let res = client.execute("ban griefer").await;
+
+        assert!(matches!(res, Err(RconError::Timeout)), "{:?}", res);
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn execute_resends_idempotent_commands_after_partial_send() {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let config = retrying_config(RetryPolicy::new().non_idempotent_prefix("ban"));
+        let mut client = RconClient::connect_with(config, stalled_then_echo_connector(Arc::clone(&connects))).await.unwrap();
+
+        assert_eq!(client.execute("status of everything").await.unwrap(), "status of everything");
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn execute_splits_long_commands_on_any_transport() {
+        let mut client = RconClient::connect_with(test_support::config(), echo_connector(Arc::default())).await.unwrap();
+
+        let command = "a".repeat(600);
+        assert_eq!(client.execute(&command).await.unwrap(), command);
+    }
+
+    struct Slow;
+
+    impl CommandHandler for Slow {
+        async fn handle(&self, command: String) -> String {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            command
+        }
+    }
+
+    #[tokio::test]
+    async fn execute_with_overrides_io_timeout_for_one_call() {
+        let config = test_support::config()
+            .io_timeout(Duration::from_millis(50))
+            .response_framing(ResponseFraming::Mirror);
+        let mut client = RconClient::connect_with(config, slow_connector()).await.unwrap();
+
+        let options = ExecOptions::new().io_timeout(Duration::from_millis(500));
+        assert_eq!(client.execute_with("cvarlist", options).await.unwrap(), "cvarlist");
+        assert!(client.execute("cvarlist").await.is_err());
+        assert_eq!(client.client_config.io_timeout, Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn execute_with_overrides_reconnects_for_one_call() {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let config = retrying_config(RetryPolicy::new()).auto_reconnect(false);
+        let mut client = RconClient::connect_with(config, silent_then_echo_connector(Arc::clone(&connects))).await.unwrap();
+
+        let options = ExecOptions::new().auto_reconnect(true).max_reconnect_attempts(2);
+        assert_eq!(client.execute_with("status", options).await.unwrap(), "status");
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn execute_with_deadline_covers_retries_and_reconnects() {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let config = retrying_config(RetryPolicy::new()).max_reconnect_attempts(100);
+        let mut client = RconClient::connect_with(config, silent_connector(Arc::clone(&connects))).await.unwrap();
+
+        let start = Instant::now();
+        let res = client.execute_with("status", ExecOptions::new().timeout(Duration::from_millis(180))).await;
+
+        assert!(matches!(res, Err(RconError::Timeout)), "{:?}", res);
+        assert!(start.elapsed() < Duration::from_millis(300), "{:?}", start.elapsed());
+        assert!(connects.load(Ordering::SeqCst) > 1);
+    }
+
+    /// The first connection swallows commands, the second is slow to authenticate, and later ones echo commands back.
+    fn silent_then_slow_auth_connector(connects: Arc<AtomicUsize>) -> impl Connector<DuplexStream> {
+        move |_: &RconClientConfig| {
+            let (client_stream, server_stream) = duplex(MAX_BUFFER_SIZE);
+            match connects.fetch_add(1, Ordering::SeqCst) {
+                0 => { tokio::spawn(serve_silently(server_stream)); },
+                1 => {
+                    tokio::spawn(async move {
+                        tokio::time::sleep(Duration::from_millis(200)).await;
+                        RconServer::new("pw".to_string(), Echo).serve(server_stream).await
+                    });
+                },
+                _ => { tokio::spawn(async move { RconServer::new("pw".to_string(), Echo).serve(server_stream).await }); },
+            }
+            async move { Ok(client_stream) }
+        }
+    }
+
+    #[tokio::test]
+    async fn deadline_during_reconnect_reconnects_before_the_next_command() {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let config = retrying_config(RetryPolicy::new()).io_timeout(Duration::from_millis(500));
+        let mut client = RconClient::connect_with(config, silent_then_slow_auth_connector(Arc::clone(&connects))).await.unwrap();
+        let mut events = client.subscribe();
+
+        let options = ExecOptions::new().io_timeout(Duration::from_millis(50)).timeout(Duration::from_millis(150));
+        let res = client.execute_with("status", options).await;
+        assert!(matches!(res, Err(RconError::Timeout)), "{:?}", res);
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+
+        assert_eq!(client.execute("status").await.unwrap(), "status");
+        assert_eq!(connects.load(Ordering::SeqCst), 3);
+        assert!(events.try_recv().is_err());
+    }
+
+    fn slow_connector() -> impl Connector<DuplexStream> {
+        serve_connector(RconServer::new("pw".to_string(), Slow), Arc::default())
+    }
+
+    #[tokio::test]
+    async fn cancelled_execute_does_not_poison_connection() {
+        // With lenient ids, the late response to the cancelled command is only told apart by resyncing
+        let config = test_support::config()
+            .io_timeout(Duration::from_secs(1))
+            .idle_timeout(Duration::from_millis(150))
+            .lenient_ids(true);
+        let mut client = RconClient::connect_with(config, slow_connector()).await.unwrap();
+
+        assert!(tokio::time::timeout(Duration::from_millis(20), client.execute("first")).await.is_err());
+
+        assert_eq!(client.execute("second").await.unwrap(), "second");
+        assert_eq!(client.execute("third").await.unwrap(), "third");
+    }
+
+    #[tokio::test]
+    async fn cancelled_execute_response_is_not_published() {
+        let config = test_support::config()
+            .io_timeout(Duration::from_secs(1))
+            .response_framing(ResponseFraming::Mirror);
+        let mut client = RconClient::connect_with(config, slow_connector()).await.unwrap();
+        let mut events = client.subscribe();
+
+        assert!(tokio::time::timeout(Duration::from_millis(20), client.execute("first")).await.is_err());
+
+        assert_eq!(client.execute("second").await.unwrap(), "second");
+        assert!(events.try_recv().is_err());
+        assert!(client.in_flight.is_none());
+    }
+}
@@ -0,0 +1,329 @@
+//! factorio.rs
+//!
+//! Factorio specific helpers on top of `RconClient`.
+//!
+//! Lua is run through `/silent-command`. Scripts too long for a single command are uploaded in
+//! pieces into a temporary global table, which is joined, loaded and cleared by a final command.
+//! Each piece is assigned by index, so a piece which is sent again on retry does not corrupt the script.
+//! Every upload gets a global of its own, so scripts uploaded at the same time by other connections,
+//! eg: from an `RconPool`, are not mixed up.
+
+use std::fmt;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{client::RconClient, errors::RconError};
+
+const LUA_COMMAND: &str = "/silent-command ";
+const SCRIPT_GLOBAL_PREFIX: &str = "__rcon_script_";
+/// Room left for the piece index in `__rcon_script[i] = `.
+const MAX_INDEX_DIGITS: usize = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Player {
+    pub name: String,
+    pub online: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FactorioVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl fmt::Display for FactorioVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+#[derive(Debug)]
+pub struct FactorioClient<S> {
+    client: RconClient<S>,
+}
+
+impl<S> FactorioClient<S> {
+    pub fn new(client: RconClient<S>) -> Self {
+        FactorioClient { client }
+    }
+
+    pub fn into_inner(self) -> RconClient<S> {
+        self.client
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> FactorioClient<S> {
+    pub async fn execute(&mut self, command: &str) -> Result<String, RconError> {
+        self.client.execute(command).await
+    }
+
+    /// Runs a Lua script through `/silent-command`, returning anything it sent with `rcon.print`.
+    pub async fn run_lua(&mut self, script: &str) -> Result<String, RconError> {
+        let max_command_size = self.client.client_config.dialect.max_command_size();
+        let command = format!("{}{}", LUA_COMMAND, script);
+        if command.len() <= max_command_size {
+            return self.client.execute(&command).await;
+        }
+
+        let global = format!("{}{:016x}", SCRIPT_GLOBAL_PREFIX, fastrand::u64(..));
+        let prefix_size = format!("{}{}[] = ", LUA_COMMAND, global).len() + MAX_INDEX_DIGITS;
+        let pieces = split_lua_string(script, max_command_size - prefix_size);
+        log::debug!("Lua script exceeds {} bytes ({}), uploading in {} pieces", max_command_size, command.len(), pieces.len());
+
+        if let Err(e) = self.upload(&global, &pieces).await {
+            // Best effort, the connection may be what failed
+            let _ = self.client.execute(&format!("{}{} = nil", LUA_COMMAND, global)).await;
+            return Err(e);
+        }
+
+        self.client.execute(&format!(
+            "{cmd}local f, err = load(table.concat({g})) {g} = nil if f then f() else rcon.print(err) end",
+            cmd = LUA_COMMAND,
+            g = global,
+        )).await
+    }
+
+    async fn upload(&mut self, global: &str, pieces: &[String]) -> Result<(), RconError> {
+        self.client.execute(&format!("{}{} = {{}}", LUA_COMMAND, global)).await?;
+        for (i, piece) in pieces.iter().enumerate() {
+            self.client.execute(&format!("{}{}[{}] = {}", LUA_COMMAND, global, i + 1, piece)).await?;
+        }
+        Ok(())
+    }
+
+    /// Lists every player who has joined the game, with whether they are online.
+    pub async fn players(&mut self) -> Result<Vec<Player>, RconError> {
+        let output = self.client.execute("/players").await?;
+        Ok(parse_players(&output))
+    }
+
+    pub async fn admins(&mut self) -> Result<Vec<Player>, RconError> {
+        let output = self.client.execute("/admins").await?;
+        Ok(parse_players(&output))
+    }
+
+    pub async fn version(&mut self) -> Result<FactorioVersion, RconError> {
+        let output = self.client.execute("/version").await?;
+        parse_version(&output)
+    }
+}
+
+/// Parses the output of `/players` or `/admins`, eg:
+/// ```text
+/// Players (2):
+///   alice (online)
+///   bob
+/// ```
+pub fn parse_players(output: &str) -> Vec<Player> {
+    output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.ends_with(':'))
+        .map(|line| match line.strip_suffix("(online)") {
+            Some(name) => Player { name: name.trim_end().to_string(), online: true },
+            None => Player { name: line.to_string(), online: false },
+        })
+        .collect()
+}
+
+/// Parses the output of `/version`, eg: `1.1.110` or `2.0.28 (build 80298, linux64, headless)`.
+pub fn parse_version(output: &str) -> Result<FactorioVersion, RconError> {
+    output
+        .split_whitespace()
+        .find_map(|token| {
+            let mut parts = token.split('.').map(|p| p.parse::<u32>().ok());
+            match (parts.next()??, parts.next()??, parts.next()??, parts.next()) {
+                (major, minor, patch, None) => Some(FactorioVersion { major, minor, patch }),
+                _ => None,
+            }
+        })
+        .ok_or_else(|| RconError::Protocol(format!("Unexpected /version output: {:?}", output)))
+}
+
+/// Splits `s` into quoted Lua string literals, each at most `max_len` bytes long.
+fn split_lua_string(s: &str, max_len: usize) -> Vec<String> {
+    let mut pieces = Vec::new();
+    let mut piece = String::from("\"");
+
+    for c in s.chars() {
+        let escaped = match c {
+            '\\' => "\\\\".to_string(),
+            '"' => "\\\"".to_string(),
+            '\n' => "\\n".to_string(),
+            '\r' => "\\r".to_string(),
+            // Three digits, so a digit after the NUL is not read as part of the escape
+            '\0' => "\\000".to_string(),
+            c => c.to_string(),
+        };
+
+        if piece.len() + escaped.len() + 1 > max_len {
+            piece.push('"');
+            pieces.push(piece);
+            piece = String::from("\"");
+        }
+        piece.push_str(&escaped);
+    }
+    piece.push('"');
+    pieces.push(piece);
+
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::server::{CommandHandler, RconServer};
+    use crate::test_support::{self, serve_connector};
+
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl CommandHandler for Recorder {
+        async fn handle(&self, command: String) -> String {
+            self.0.lock().unwrap().push(command.clone());
+            match command.as_str() {
+                "/version" => "2.0.28 (build 80298, linux64, headless)".to_string(),
+                _ => "ok".to_string(),
+            }
+        }
+    }
+
+    async fn recording_client() -> (FactorioClient<tokio::io::DuplexStream>, Arc<Mutex<Vec<String>>>) {
+        let commands = Arc::new(Mutex::new(Vec::new()));
+        let server = RconServer::new("pw".to_string(), Recorder(Arc::clone(&commands)));
+        let client = RconClient::connect_with(test_support::config(), serve_connector(server, Arc::default())).await.unwrap();
+
+        (FactorioClient::new(client), commands)
+    }
+
+    /// Unquotes a Lua string literal, reading decimal escapes greedily as Lua does.
+    fn unquote_lua(piece: &str) -> String {
+        let mut out = String::new();
+        let mut chars = piece[1..piece.len() - 1].chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                out.push(c);
+                continue;
+            }
+            match chars.next().unwrap() {
+                'n' => out.push('\n'),
+                'r' => out.push('\r'),
+                d if d.is_ascii_digit() => {
+                    let mut code = d.to_digit(10).unwrap();
+                    for _ in 0..2 {
+                        match chars.next_if(char::is_ascii_digit) {
+                            Some(d) => code = code * 10 + d.to_digit(10).unwrap(),
+                            None => break,
+                        }
+                    }
+                    out.push(char::from_u32(code).unwrap());
+                },
+                c => out.push(c),
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn split_lua_string_escapes_and_respects_limit() {
+        let script = "rcon.print(\"a\\b\")\n".repeat(50);
+
+        let pieces = split_lua_string(&script, 64);
+
+        assert!(pieces.iter().all(|p| p.len() <= 64));
+        assert_eq!(pieces.iter().map(|p| unquote_lua(p)).collect::<String>(), script);
+    }
+
+    #[test]
+    fn split_lua_string_escapes_nul_before_digit() {
+        let pieces = split_lua_string("a\u{0}1", 64);
+
+        assert_eq!(pieces, vec!["\"a\\0001\""]);
+        assert_eq!(unquote_lua(&pieces[0]), "a\u{0}1");
+    }
+
+    #[test]
+    fn parse_players_reads_online_status() {
+        let output = "Players (3):\n  alice (online)\n  bob\n  carol (online)\n";
+
+        assert_eq!(parse_players(output), vec![
+            Player { name: "alice".to_string(), online: true },
+            Player { name: "bob".to_string(), online: false },
+            Player { name: "carol".to_string(), online: true },
+        ]);
+    }
+
+    #[test]
+    fn parse_players_handles_no_players() {
+        assert!(parse_players("Players (0):\n").is_empty());
+        assert!(parse_players("").is_empty());
+    }
+
+    #[test]
+    fn parse_version_reads_plain_and_build_output() {
+        let expected = FactorioVersion { major: 1, minor: 1, patch: 110 };
+        assert_eq!(parse_version("1.1.110").unwrap(), expected);
+        assert_eq!(parse_version("Version: 1.1.110 (build 61234, linux64, headless)").unwrap(), expected);
+        assert!(matches!(parse_version("unknown command"), Err(RconError::Protocol(_))));
+    }
+
+    #[tokio::test]
+    async fn run_lua_sends_short_scripts_as_one_command() {
+        let (mut client, commands) = recording_client().await;
+
+        assert_eq!(client.run_lua("rcon.print(game.tick)").await.unwrap(), "ok");
+        assert_eq!(*commands.lock().unwrap(), vec!["/silent-command rcon.print(game.tick)"]);
+    }
+
+    #[tokio::test]
+    async fn run_lua_uploads_long_scripts_in_pieces() {
+        let (mut client, commands) = recording_client().await;
+        let script = "game.print(\"hello \\\"world\\\"\")\n".repeat(40);
+
+        client.run_lua(&script).await.unwrap();
+
+        let commands = commands.lock().unwrap();
+        assert!(commands.iter().all(|c| c.len() <= 511));
+        let global = uploaded_global(&commands[0]);
+        assert!(commands.last().unwrap().contains(&format!("load(table.concat({g})) {g} = nil", g = global)));
+
+        let uploaded: String = commands[1..commands.len() - 1]
+            .iter()
+            .enumerate()
+            .map(|(i, c)| {
+                let prefix = format!("/silent-command {}[{}] = ", global, i + 1);
+                unquote_lua(c.strip_prefix(&prefix).unwrap())
+            })
+            .collect();
+        assert_eq!(uploaded, script);
+    }
+
+    /// The global a long script is uploaded into, from the command creating it.
+    fn uploaded_global(command: &str) -> &str {
+        let global = command.strip_prefix("/silent-command ").unwrap().strip_suffix(" = {}").unwrap();
+        assert!(global.starts_with(SCRIPT_GLOBAL_PREFIX), "{}", command);
+        global
+    }
+
+    #[tokio::test]
+    async fn run_lua_uploads_each_script_into_its_own_global() {
+        let (mut client, commands) = recording_client().await;
+        let script = "game.print(1)\n".repeat(100);
+
+        client.run_lua(&script).await.unwrap();
+        let first = commands.lock().unwrap().len();
+        client.run_lua(&script).await.unwrap();
+
+        let commands = commands.lock().unwrap();
+        assert_ne!(uploaded_global(&commands[0]), uploaded_global(&commands[first]));
+    }
+
+    #[tokio::test]
+    async fn version_parses_server_output() {
+        let (mut client, _) = recording_client().await;
+
+        assert_eq!(client.version().await.unwrap(), FactorioVersion { major: 2, minor: 0, patch: 28 });
+    }
+}
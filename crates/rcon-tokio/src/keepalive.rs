@@ -0,0 +1,269 @@
+//! keepalive.rs
+//!
+//! Keeps long-lived connections from silently dying behind NATs and firewalls, which otherwise only
+//! shows up as the next real command timing out.
+//!
+//! Probes are sent by calling `keepalive` periodically, eg: from a `tokio::time::interval` alongside
+//! the rest of a bot's work, or by a background task started with `spawn_keepalive` for a client
+//! shared behind a mutex. TCP keepalive is enabled on the socket as well, so the OS notices dead
+//! peers between probes. Probes take a token from the rate limiter like any other command.
+
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use socket2::{SockRef, TcpKeepalive};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+use crate::{client::RconClient, errors::RconError, exec_options::ExecOptions, trace};
+
+impl<S: AsyncRead + AsyncWrite + Unpin> RconClient<S> {
+    /// How long since a packet was last sent or received on the connection.
+    pub fn idle_for(&self) -> Duration {
+        self.last_activity.elapsed()
+    }
+
+    /// Sends the configured `keepalive_command` if the connection has been idle for at least `keepalive_interval`.
+    /// Does nothing when keepalive is not configured, or the connection was used recently.
+    ///
+    /// If the probe fails, the client reconnects when `auto_reconnect` is set.
+    /// Otherwise, or if reconnecting fails, the connection is reported dead with the error.
+    pub async fn keepalive(&mut self) -> Result<(), RconError> {
+        let Some(interval) = self.client_config.keepalive_interval else {
+            return Ok(());
+        };
+        if self.idle_for() < interval {
+            return Ok(());
+        }
+
+        log::debug!("Connection idle for {:?}, sending keepalive probe", self.idle_for());
+        let command = self.client_config.keepalive_command.clone().into_bytes();
+        let span = trace::execute_span(&self.client_config);
+        let probe = trace::in_span(span, async {
+            self.throttle(&command).await?;
+            let options = ExecOptions::default();
+            let (cmd_id, mirror_id) = self.send_command(&command, &options).await?;
+            self.read_response(cmd_id, mirror_id, &options).await
+        }).await;
+
+        match probe {
+            Ok(_) => Ok(()),
+            Err(e) if self.client_config.auto_reconnect => {
+                log::warn!("Keepalive probe failed, reconnecting: {}", e);
+                self.reconnect().await
+            },
+            Err(e) => {
+                log::warn!("Keepalive probe failed, connection is dead: {}", e);
+                Err(e)
+            },
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> RconClient<S> {
+    /// Starts a background task which calls `keepalive` whenever `client` has been idle for `keepalive_interval`.
+    /// The task locks the client only while probing, and stops when the returned handle is dropped.
+    ///
+    /// Once a probe finds the connection dead, and reconnecting failed or `auto_reconnect` is not set,
+    /// the task stops and `KeepaliveHandle::dead` returns the error.
+    pub fn spawn_keepalive(client: Arc<Mutex<RconClient<S>>>) -> KeepaliveHandle {
+        KeepaliveHandle { task: Some(tokio::spawn(keepalive_loop(client))) }
+    }
+}
+
+/// Handle to the background task started by `RconClient::spawn_keepalive`, which is stopped when this is dropped.
+#[derive(Debug)]
+pub struct KeepaliveHandle {
+    task: Option<JoinHandle<RconError>>,
+}
+
+impl KeepaliveHandle {
+    /// Waits until the task finds the connection dead, and returns the error.
+    /// Once the error has been returned, later calls return `ClientError` straight away.
+    pub async fn dead(&mut self) -> RconError {
+        let Some(task) = self.task.as_mut() else {
+            return RconError::ClientError("Connection is dead".to_string());
+        };
+        let res = task.await;
+        self.task = None;
+
+        res.unwrap_or_else(|e| RconError::ClientError(format!("Keepalive task failed: {}", e)))
+    }
+
+    /// Whether the task has found the connection dead, and stopped.
+    pub fn is_dead(&self) -> bool {
+        self.task.as_ref().is_none_or(|task| task.is_finished())
+    }
+}
+
+impl Drop for KeepaliveHandle {
+    fn drop(&mut self) {
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+    }
+}
+
+async fn keepalive_loop<S: AsyncRead + AsyncWrite + Unpin>(client: Arc<Mutex<RconClient<S>>>) -> RconError {
+    loop {
+        let wait = {
+            let mut client = client.lock().await;
+            let Some(interval) = client.client_config.keepalive_interval else {
+                return RconError::ClientError("Keepalive is not configured, see RconClientConfig::keepalive".to_string());
+            };
+            if let Err(e) = client.keepalive().await {
+                return e;
+            }
+            interval.saturating_sub(client.idle_for())
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Enables TCP keepalive, probing the peer once the socket has been idle for `interval`.
+pub(crate) fn set_tcp_keepalive(stream: &TcpStream, interval: Duration) -> io::Result<()> {
+    let keepalive = TcpKeepalive::new().with_time(interval);
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+    let keepalive = keepalive.with_interval(interval);
+
+    SockRef::from(stream).set_tcp_keepalive(&keepalive)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use tokio::io::{duplex, DuplexStream};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::connect::{Connector, TcpConnector};
+    use crate::server::RconServer;
+    use crate::test_support::{self, echo_connector, serve_connector, Counted, Echo, MAX_BUFFER_SIZE};
+    use crate::{RateLimit, RconClientConfig};
+
+    /// Echoes commands back, counting connects and commands.
+    fn counting_connector(connects: Arc<AtomicUsize>, commands: Arc<AtomicUsize>) -> impl Connector<DuplexStream> {
+        serve_connector(RconServer::new("pw".to_string(), Counted(commands, Echo)), connects)
+    }
+
+    fn config() -> RconClientConfig {
+        test_support::config().keepalive(Duration::from_millis(50))
+    }
+
+    #[tokio::test]
+    async fn keepalive_probes_only_idle_connections() {
+        let probes = Arc::new(AtomicUsize::new(0));
+        let connector = counting_connector(Arc::default(), Arc::clone(&probes));
+        let mut client = RconClient::connect_with(config(), connector).await.unwrap();
+
+        client.keepalive().await.unwrap();
+        assert_eq!(probes.load(Ordering::SeqCst), 0);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        client.keepalive().await.unwrap();
+        assert_eq!(probes.load(Ordering::SeqCst), 1);
+        assert!(client.idle_for() < Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn keepalive_reconnects_dead_connection_with_auto_reconnect() {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let connector = counting_connector(Arc::clone(&connects), Arc::default());
+        let mut client = RconClient::connect_with(config().auto_reconnect(true), connector).await.unwrap();
+
+        let (dead_stream, _) = duplex(MAX_BUFFER_SIZE);
+        client.stream = dead_stream;
+        tokio::time::sleep(Duration::from_millis(60)).await;
+
+        client.keepalive().await.unwrap();
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+        assert_eq!(client.execute("status").await.unwrap(), "status");
+    }
+
+    #[tokio::test]
+    async fn keepalive_reports_dead_connection_without_auto_reconnect() {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let connector = counting_connector(Arc::clone(&connects), Arc::default());
+        let mut client = RconClient::connect_with(config(), connector).await.unwrap();
+
+        let (dead_stream, _) = duplex(MAX_BUFFER_SIZE);
+        client.stream = dead_stream;
+        tokio::time::sleep(Duration::from_millis(60)).await;
+
+        assert!(client.keepalive().await.is_err());
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn keepalive_waits_for_rate_limit() {
+        let commands = Arc::new(AtomicUsize::new(0));
+        let connector = counting_connector(Arc::default(), Arc::clone(&commands));
+        let config = config().rate_limit(RateLimit::new(1, 5.0));
+        let mut client = RconClient::connect_with(config, connector).await.unwrap();
+
+        client.execute("status").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(60)).await;
+
+        let res = tokio::time::timeout(Duration::from_millis(50), client.keepalive()).await;
+        assert!(res.is_err());
+        // Only the command reached the server
+        assert_eq!(commands.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn spawn_keepalive_probes_idle_connection_in_background() {
+        let probes = Arc::new(AtomicUsize::new(0));
+        let connector = counting_connector(Arc::default(), Arc::clone(&probes));
+        let client = Arc::new(Mutex::new(RconClient::connect_with(config(), connector).await.unwrap()));
+
+        let handle = RconClient::spawn_keepalive(Arc::clone(&client));
+        tokio::time::sleep(Duration::from_millis(130)).await;
+
+        assert!(probes.load(Ordering::SeqCst) >= 1);
+        assert!(!handle.is_dead());
+        assert_eq!(client.lock().await.execute("status").await.unwrap(), "status");
+    }
+
+    #[tokio::test]
+    async fn spawn_keepalive_surfaces_dead_connection() {
+        let connector = echo_connector(Arc::default());
+        let mut client = RconClient::connect_with(config(), connector).await.unwrap();
+        let (dead_stream, _) = duplex(MAX_BUFFER_SIZE);
+        client.stream = dead_stream;
+
+        let mut handle = RconClient::spawn_keepalive(Arc::new(Mutex::new(client)));
+        let err = tokio::time::timeout(Duration::from_secs(1), handle.dead()).await.unwrap();
+
+        assert!(matches!(err, RconError::Io(_) | RconError::Timeout), "{:?}", err);
+        assert!(handle.is_dead());
+        assert!(matches!(handle.dead().await, RconError::ClientError(_)));
+    }
+
+    #[tokio::test]
+    async fn spawn_keepalive_fails_without_keepalive_interval() {
+        let connector = echo_connector(Arc::default());
+        let config = RconClientConfig { keepalive_interval: None, ..config() };
+        let client = RconClient::connect_with(config, connector).await.unwrap();
+
+        let mut handle = RconClient::spawn_keepalive(Arc::new(Mutex::new(client)));
+        assert!(matches!(handle.dead().await, RconError::ClientError(_)));
+    }
+
+    #[tokio::test]
+    async fn tcp_connector_enables_tcp_keepalive() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = RconClientConfig::new(addr.ip().to_string(), addr.port(), "pw".to_string());
+
+        let stream = TcpConnector.connect(&config).await.unwrap();
+        assert!(!SockRef::from(&stream).keepalive().unwrap());
+
+        let stream = TcpConnector.connect(&config.keepalive(Duration::from_secs(30))).await.unwrap();
+        assert!(SockRef::from(&stream).keepalive().unwrap());
+    }
+}
@@ -1,11 +1,84 @@
+#[cfg(not(any(feature = "async", feature = "blocking")))]
+compile_error!("rcon-tokio needs the `async` or `blocking` feature enabled");
+
 mod common;
 mod packet;
-pub mod client;
+mod response;
 pub mod errors;
+pub mod client_config;
+pub mod encoding;
+pub mod retry;
+pub mod dialect;
+pub mod proxy;
+pub mod rate_limit;
+#[cfg(feature = "async")]
+pub mod client;
+#[cfg(feature = "async")]
 pub mod connect;
+#[cfg(feature = "async")]
 pub mod execute;
-pub mod client_config;
+#[cfg(feature = "async")]
+pub mod exec_options;
+#[cfg(feature = "async")]
+pub mod keepalive;
+#[cfg(feature = "async")]
+pub mod stats;
+#[cfg(feature = "async")]
+mod trace;
+#[cfg(feature = "async")]
+pub mod net;
+#[cfg(feature = "async")]
 pub mod client_io;
+#[cfg(feature = "async")]
+pub mod codec;
+#[cfg(feature = "async")]
+pub mod server;
+#[cfg(feature = "async")]
+pub mod pipeline;
+#[cfg(feature = "async")]
+pub mod pool;
+#[cfg(feature = "async")]
+pub mod factorio;
+#[cfg(feature = "async")]
+pub mod process;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+#[cfg(feature = "battleye")]
+pub mod battleye;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "webrcon")]
+pub mod webrcon;
+#[cfg(all(test, feature = "async"))]
+mod test_support;
 
-pub use client_config::RconClientConfig;
-pub use client::RconClient;
\ No newline at end of file
+pub use client_config::{RconClientConfig, ResponseFraming};
+pub use encoding::ResponseEncoding;
+pub use retry::RetryPolicy;
+pub use dialect::Dialect;
+pub use proxy::Proxy;
+pub use rate_limit::RateLimit;
+pub use common::{Direction, PacketType, Phase};
+pub use packet::Packet;
+#[cfg(feature = "async")]
+pub use client::RconClient;
+#[cfg(feature = "async")]
+pub use connect::{Connector, TcpConnector};
+#[cfg(feature = "async")]
+pub use exec_options::ExecOptions;
+#[cfg(feature = "async")]
+pub use net::{NetConnector, NetStream};
+#[cfg(feature = "async")]
+pub use process::{ProcessConnector, ProcessStream};
+#[cfg(feature = "async")]
+pub use server::{CommandHandler, RconServer};
+#[cfg(feature = "async")]
+pub use pipeline::PipelinedClient;
+#[cfg(feature = "async")]
+pub use pool::{PooledClient, RconPool, RconPoolConfig};
+#[cfg(feature = "async")]
+pub use keepalive::KeepaliveHandle;
+#[cfg(feature = "async")]
+pub use codec::RconCodec;
+#[cfg(feature = "metrics")]
+pub use stats::RconStats;
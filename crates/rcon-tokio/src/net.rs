@@ -0,0 +1,160 @@
+//! net.rs
+//!
+//! The stream opened by `RconClient::connect`: TCP, or a Unix domain socket when the config's address
+//! is a `unix:///path/to/socket` URL. This lets servers published on a socket, eg: by socat in a container,
+//! be reached without exposing a TCP port.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+use crate::client_config::RconClientConfig;
+use crate::connect::{ConnectFuture, Connector, TcpConnector};
+use crate::errors::RconError;
+
+#[derive(Debug)]
+pub enum NetStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl AsyncRead for NetStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            NetStream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(unix)]
+            NetStream::Unix(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for NetStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            NetStream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(unix)]
+            NetStream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            NetStream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(unix)]
+            NetStream::Unix(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            NetStream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(unix)]
+            NetStream::Unix(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Connects to a `unix://` address over a Unix domain socket, and to anything else with `TcpConnector`.
+/// This is the connector used by `RconClient::connect`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NetConnector;
+
+impl Connector<NetStream> for NetConnector {
+    fn connect<'a>(&'a self, config: &'a RconClientConfig) -> ConnectFuture<'a, NetStream> {
+        Box::pin(async move {
+            let Some(path) = config.unix_path() else {
+                return Ok(NetStream::Tcp(TcpConnector.connect(config).await?));
+            };
+            if config.proxy.is_some() {
+                return Err(RconError::ClientError("Proxies cannot be used with unix:// addresses".to_string()));
+            }
+
+            log::debug!("Connecting to Unix socket {}", path);
+            connect_unix(path).await
+        })
+    }
+}
+
+#[cfg(unix)]
+async fn connect_unix(path: &str) -> Result<NetStream, RconError> {
+    Ok(NetStream::Unix(UnixStream::connect(path).await?))
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(_path: &str) -> Result<NetStream, RconError> {
+    Err(RconError::ClientError("Unix sockets are not supported on this platform".to_string()))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::net::UnixListener;
+
+    use super::*;
+    use crate::client::RconClient;
+    use crate::server::RconServer;
+    use crate::test_support::{self, Echo};
+
+    fn socket_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rcon-tokio-{}-{}.sock", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    /// Serves `RconServer` on a Unix socket, counting accepted connections.
+    fn start_server(path: &Path) -> Arc<AtomicUsize> {
+        let listener = UnixListener::bind(path).unwrap();
+        let server = RconServer::new("pw".to_string(), Echo);
+        let accepted = Arc::new(AtomicUsize::new(0));
+
+        let count = Arc::clone(&accepted);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                count.fetch_add(1, Ordering::SeqCst);
+                let server = server.clone();
+                tokio::spawn(async move { server.serve(stream).await });
+            }
+        });
+        accepted
+    }
+
+    fn config(path: &Path) -> RconClientConfig {
+        RconClientConfig { address: format!("unix://{}", path.display()), ..test_support::config() }
+            .io_timeout(Duration::from_secs(1))
+    }
+
+    #[tokio::test]
+    async fn connect_and_reconnect_over_unix_socket() {
+        let path = socket_path("reconnect");
+        let accepted = start_server(&path);
+
+        let mut client = RconClient::connect(config(&path)).await.unwrap();
+        assert!(matches!(client.stream, NetStream::Unix(_)));
+        assert_eq!(client.execute("status").await.unwrap(), "status");
+
+        client.reconnect().await.unwrap();
+        assert_eq!(client.execute("again").await.unwrap(), "again");
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn connect_fails_for_missing_socket() {
+        let path = socket_path("missing");
+
+        let res = RconClient::connect(config(&path)).await;
+        assert!(matches!(res, Err(RconError::Io(_))), "{:?}", res);
+    }
+}
@@ -14,18 +14,35 @@
 //! | --------- | --------- | ------------- |
 //! 
 
+use std::fmt;
+
+use bytes::BufMut;
+#[cfg(feature = "async")]
 use tokio::io::{AsyncRead, AsyncReadExt};
 
-use crate::{common::PacketType, errors::RconError};
+use crate::{common::{Direction, PacketType, Phase}, dialect::Dialect, errors::RconError};
 
-#[derive(Debug)]
+#[derive(Clone, PartialEq, Eq)]
 pub struct Packet {
     pub id: i32,
     pub packet_type: PacketType,
-    pub body: String, // limited to 511 bytes w/o whitespace
+    pub body: Vec<u8>, // limited to 511 bytes w/o whitespace
+}
+
+/// The body of auth packets is the password, so it is redacted.
+impl fmt::Debug for Packet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = f.debug_struct("Packet");
+        s.field("id", &self.id).field("packet_type", &self.packet_type);
+        match self.packet_type {
+            PacketType::ServerDataAuth => s.field("body", &"<redacted>"),
+            _ => s.field("body", &self.body),
+        };
+        s.finish()
+    }
 }
 
-const SIZE_FIELD_SIZE: usize = 4;
+pub(crate) const SIZE_FIELD_SIZE: usize = 4;
 const ID_FIELD_SIZE: usize = 4;
 const TYPE_FIELD_SIZE: usize = 4;
 const EMPTY_PACKET_BODY_SIZE: usize = 1;
@@ -34,21 +51,27 @@ const NULL_STRING_TERMINATOR_SIZE: usize = 1;
 const MINIMUM_PAYLOAD_SIZE: usize =
     ID_FIELD_SIZE + TYPE_FIELD_SIZE + EMPTY_PACKET_BODY_SIZE + NULL_STRING_TERMINATOR_SIZE;
 
-const MAXIMUM_PACKET_SIZE: usize = 4096;
-const MAXIMUM_PAYLOAD_SIZE: usize = MAXIMUM_PACKET_SIZE - SIZE_FIELD_SIZE;
-const MAXIMUM_BODY_SIZE: usize = 511;
-
-fn assert_null_terminated_body(packet: &Vec<u8>) -> Result<(), RconError> {
+/// The bytes every packet takes on the wire besides its body.
+#[cfg_attr(not(feature = "async"), allow(dead_code))]
+pub(crate) const PACKET_OVERHEAD: usize = SIZE_FIELD_SIZE + MINIMUM_PAYLOAD_SIZE;
+pub(crate) const MAXIMUM_PACKET_SIZE: usize = 4096;
+pub(crate) const MAXIMUM_PAYLOAD_SIZE: usize = MAXIMUM_PACKET_SIZE - SIZE_FIELD_SIZE;
+pub(crate) const MAXIMUM_BODY_SIZE: usize = 511;
+/// Only used by the server.
+#[cfg_attr(not(feature = "async"), allow(dead_code))]
+pub(crate) const MAXIMUM_RESPONSE_BODY_SIZE: usize = MAXIMUM_PAYLOAD_SIZE - MINIMUM_PAYLOAD_SIZE;
+
+fn assert_null_terminated_body(packet: &[u8]) -> Result<(), RconError> {
     let raw_body = &packet[8..];
 
     if raw_body.len() < 2 {
-        return Err(RconError::Protocol(format!("Payload body too small")));
+        return Err(RconError::Protocol("Payload body too small".to_string()));
     }
     if raw_body[raw_body.len() - 2] != 0 {
-        return Err(RconError::Protocol(format!("Body missing null terminator")));
+        return Err(RconError::Protocol("Body missing null terminator".to_string()));
     }
     if raw_body[raw_body.len() - 1] != 0 {
-        return Err(RconError::Protocol(format!("Packet missing null terminator")));
+        return Err(RconError::Protocol("Packet missing null terminator".to_string()));
     }
 
     Ok(())
@@ -61,61 +84,107 @@ fn assert_null_terminated_body(packet: &Vec<u8>) -> Result<(), RconError> {
 ///   - Max packet size = 4096
 ///   - Packet Structure: [Size: 4bytes, ID: 4bytes, Type: 4bytes, Body:0..4086bytes, terminating string: 1byte]
 /// 
-pub fn build_packet(id: i32, kind: PacketType, body: &str) -> Result<Vec<u8>, RconError> {
-    let body_bytes = body.as_bytes();
-    let payload_size: usize = MINIMUM_PAYLOAD_SIZE + body_bytes.len();
+pub fn build_packet(id: i32, kind: PacketType, body: &[u8], dialect: Dialect) -> Result<Vec<u8>, RconError> {
+    let mut buffer = Vec::with_capacity(SIZE_FIELD_SIZE + MINIMUM_PAYLOAD_SIZE + body.len());
+    encode_packet(id, kind, body, dialect, &mut buffer)?;
+
+    assert_null_terminated_body(&buffer)?;
+    Ok(buffer)
+}
 
-    if payload_size > MAXIMUM_PAYLOAD_SIZE {
+/// Like `build_packet`, appending the packet to `buffer`.
+pub(crate) fn encode_packet<B: BufMut>(
+    id: i32,
+    kind: PacketType,
+    body: &[u8],
+    dialect: Dialect,
+    buffer: &mut B,
+) -> Result<(), RconError> {
+    let payload_size: usize = MINIMUM_PAYLOAD_SIZE + body.len();
+
+    if payload_size > dialect.max_payload_size() {
         return Err(RconError::Protocol(format!("[WRITE] payload size is too large: {}", payload_size)));
     }
-    if body.len() > MAXIMUM_BODY_SIZE {
-        return Err(RconError::Protocol(format!("[WRITE] packet body exceeds 511 chars: {}", body.len())))
+    // Only requests are bound by the command size limit, servers may fill the whole packet.
+    let is_request = matches!(kind, PacketType::ServerDataAuth | PacketType::ServerDataExecCommand);
+    if is_request && body.len() > dialect.max_command_size() {
+        return Err(RconError::Protocol(
+            format!("[WRITE] packet body exceeds {} bytes: {}", dialect.max_command_size(), body.len())
+        ))
     }
 
-    let total_size = SIZE_FIELD_SIZE + payload_size;
-    let mut buffer = Vec::with_capacity(total_size);
+    buffer.put_i32_le(payload_size as i32);
+    buffer.put_i32_le(id);
+    buffer.put_i32_le(kind.into());
+    buffer.put_slice(body);
+    buffer.put_u8(0);
+    buffer.put_u8(0);
 
-    buffer.extend_from_slice(&(payload_size as i32).to_le_bytes());
-    buffer.extend_from_slice(&id.to_le_bytes());
-
-    let kind_i32: i32 = kind.into();
-    buffer.extend_from_slice(&kind_i32.to_le_bytes());
+    Ok(())
+}
 
-    buffer.extend_from_slice(body_bytes);
+/// Read a packet according to the valve docs specifications, with the size limits of `dialect`.
+/// The packet type is decoded for a packet sent in `direction` during `phase`.
+#[cfg(feature = "async")]
+pub async fn read_packet<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    dialect: Dialect,
+    direction: Direction,
+    phase: Phase,
+) -> Result<Packet, RconError> {
+    let mut size_bytes = [0u8; 4];
+    stream.read_exact(&mut size_bytes).await?;
+    let size = payload_size(size_bytes, dialect)?;
 
-    buffer.push(0);
-    buffer.push(0);
+    let mut payload = vec![0u8; size];
+    stream.read_exact(&mut payload).await?;
 
-    assert_null_terminated_body(&buffer)?;
-    return Ok(buffer);
+    parse_payload(&payload, direction, phase)
 }
 
-/// Read a packet according to the valve docs specifications. 
-pub async fn read_packet<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Packet, RconError> {
+/// Like `read_packet`, from a blocking reader.
+#[cfg(feature = "blocking")]
+pub fn read_packet_blocking<R: std::io::Read>(
+    reader: &mut R,
+    dialect: Dialect,
+    direction: Direction,
+    phase: Phase,
+) -> Result<Packet, RconError> {
     let mut size_bytes = [0u8; 4];
-    stream.read_exact(&mut size_bytes).await?;
+    reader.read_exact(&mut size_bytes)?;
+    let size = payload_size(size_bytes, dialect)?;
+
+    let mut payload = vec![0u8; size];
+    reader.read_exact(&mut payload)?;
+
+    parse_payload(&payload, direction, phase)
+}
+
+/// Decodes the size field of a packet, checking it against the limits of `dialect`.
+pub(crate) fn payload_size(size_bytes: [u8; 4], dialect: Dialect) -> Result<usize, RconError> {
     let size = i32::from_le_bytes(size_bytes) as usize;
 
     if size < MINIMUM_PAYLOAD_SIZE {
         return Err(RconError::Protocol(format!("[READ] packet size too small: {}", size)));
-    } else if size > MAXIMUM_PACKET_SIZE {
+    } else if size > dialect.max_packet_size() {
         return Err(RconError::Protocol(format!("[READ] packet size too large: {}", size)));
     }
+    Ok(size)
+}
 
-    let mut payload = vec![0u8; size];
-    stream.read_exact(&mut payload).await?;
-
-    assert_null_terminated_body(&payload)?;
+/// Decodes the part of a packet following its size field.
+pub(crate) fn parse_payload(payload: &[u8], direction: Direction, phase: Phase) -> Result<Packet, RconError> {
+    assert_null_terminated_body(payload)?;
 
     let id = i32::from_le_bytes(payload[0..4].try_into().unwrap());
     let kind_i32 = i32::from_le_bytes(payload[4..8].try_into().unwrap());
-    let packet_type = PacketType::from_i32(kind_i32, false);
+    let packet_type = PacketType::decode(kind_i32, direction, phase);
 
     let raw_body = &payload[8..];
     let end = raw_body.iter()
         .position(|&b| b == 0)
         .unwrap_or(raw_body.len());
-    let body = String::from_utf8(raw_body[..end].to_vec())?;
+    let body = raw_body[..end].to_vec();
 
     Ok(Packet { id, packet_type, body })
 }
@@ -123,10 +192,21 @@ pub async fn read_packet<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Packet,
 
 #[cfg(test)]
 mod tests {
+    #[cfg(feature = "async")]
     use std::io::Cursor;
 
     use super::*;
 
+    #[test]
+    fn debug_redacts_auth_packet_body() {
+        let auth = Packet { id: 1, packet_type: PacketType::ServerDataAuth, body: b"hunter2".to_vec() };
+        let command = Packet { id: 2, packet_type: PacketType::ServerDataExecCommand, body: b"hi".to_vec() };
+
+        assert!(!format!("{:?}", auth).contains(&format!("{:?}", b"hunter2".to_vec())));
+        assert!(format!("{:?}", auth).contains("<redacted>"));
+        assert!(format!("{:?}", command).contains(&format!("{:?}", b"hi".to_vec())));
+    }
+
     #[test]
     fn build_packet_encodes_header_body_and_trailer() {
         // Arrange
@@ -135,11 +215,11 @@ mod tests {
         let body = "hi";
 
         // Act
-        let pkt = build_packet(id, packet_type.clone(), body).unwrap();
+        let pkt = build_packet(id, packet_type, body.as_bytes(), Dialect::Source).unwrap();
 
         // Assert: compute expected bytes
         let kind_i32: i32 = packet_type.into();
-        let expected_size: i32 = (MINIMUM_PAYLOAD_SIZE + body.as_bytes().len()) as i32;
+        let expected_size: i32 = (MINIMUM_PAYLOAD_SIZE + body.len()) as i32;
 
         let mut expected = Vec::new();
         expected.extend_from_slice(&expected_size.to_le_bytes());
@@ -164,9 +244,9 @@ mod tests {
         let packet_type = PacketType::ServerDataAuth;
         let body = "";
 
-        let pkt = build_packet(id, packet_type, body).unwrap();
+        let pkt = build_packet(id, packet_type, body.as_bytes(), Dialect::Source).unwrap();
 
-        let expected_size = (MINIMUM_PAYLOAD_SIZE + 0) as i32;
+        let expected_size = MINIMUM_PAYLOAD_SIZE as i32;
         assert_eq!(i32::from_le_bytes(pkt[0..4].try_into().unwrap()), expected_size);
         assert_eq!(&pkt[pkt.len()-2..], &[0, 0]);
         assert_eq!(&pkt[12..pkt.len()-2], b"");
@@ -181,27 +261,47 @@ mod tests {
         let body_len = MAXIMUM_PAYLOAD_SIZE - MINIMUM_PAYLOAD_SIZE + 1;
         let body = "a".repeat(body_len);
 
-        let result = build_packet(id, kind, &body);
+        let result = build_packet(id, kind, body.as_bytes(), Dialect::Source);
 
         assert!(matches!(result, Err(RconError::Protocol(_))));
     }
 
+    #[test]
+    fn build_packet_rejects_request_body_over_511_bytes() {
+        let body = "a".repeat(MAXIMUM_BODY_SIZE + 1);
+
+        let result = build_packet(1, PacketType::ServerDataExecCommand, body.as_bytes(), Dialect::Source);
+
+        assert!(matches!(result, Err(RconError::Protocol(_))));
+    }
+
+    #[test]
+    fn build_packet_allows_full_size_response_body() {
+        let body = "a".repeat(MAXIMUM_RESPONSE_BODY_SIZE);
+
+        let pkt = build_packet(1, PacketType::ServerDataResponseValue, body.as_bytes(), Dialect::Source).unwrap();
+
+        assert_eq!(pkt.len(), MAXIMUM_PACKET_SIZE);
+    }
+
+    #[cfg(feature = "async")]
     #[tokio::test]
     async fn read_packet_parses_valid_packet_from_stream() {
         let id = 123;
         let packet_type = PacketType::ServerDataAuth;
         let body = "hello";
 
-        let bytes = build_packet(id, packet_type.clone(), body).unwrap();
+        let bytes = build_packet(id, packet_type, body.as_bytes(), Dialect::Source).unwrap();
         let mut cur = Cursor::new(bytes);
 
-        let pkt = read_packet(&mut cur).await.unwrap();
+        let pkt = read_packet(&mut cur, Dialect::Source, Direction::ClientToServer, Phase::Auth).await.unwrap();
 
         assert_eq!(pkt.id, id);
         assert_eq!(pkt.packet_type, packet_type);
-        assert_eq!(pkt.body, body);
+        assert_eq!(pkt.body, body.as_bytes());
     }
 
+    #[cfg(feature = "async")]
     #[tokio::test]
     async fn read_packet_rejects_declared_size_over_max() {
         let declared_size = (MAXIMUM_PACKET_SIZE as i32) + 1;
@@ -216,17 +316,61 @@ mod tests {
         bytes.extend_from_slice(&[0,0]);
 
         let mut cur = Cursor::new(bytes);
-        let err = read_packet(&mut cur).await.unwrap_err();
+        let err = read_packet(&mut cur, Dialect::Source, Direction::ClientToServer, Phase::Auth).await.unwrap_err();
         
         assert!(matches!(err, RconError::Protocol(_)));
     }
 
+    #[test]
+    fn build_packet_allows_longer_minecraft_commands() {
+        let body = "a".repeat(1446);
+
+        assert!(build_packet(1, PacketType::ServerDataExecCommand, body.as_bytes(), Dialect::Minecraft).is_ok());
+        assert!(build_packet(1, PacketType::ServerDataExecCommand, body.as_bytes(), Dialect::Source).is_err());
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn read_packet_accepts_large_minecraft_fragments() {
+        let body = "é".repeat(4096);
+        let size = (MINIMUM_PAYLOAD_SIZE + body.len()) as i32;
+        let type_i32: i32 = PacketType::ServerDataResponseValue.into();
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&size.to_le_bytes());
+        bytes.extend_from_slice(&1i32.to_le_bytes());
+        bytes.extend_from_slice(&type_i32.to_le_bytes());
+        bytes.extend_from_slice(body.as_bytes());
+        bytes.extend_from_slice(&[0, 0]);
+
+        let pkt = read_packet(&mut Cursor::new(bytes.clone()), Dialect::Minecraft, Direction::ServerToClient, Phase::Command).await.unwrap();
+        assert_eq!(pkt.body, body.as_bytes());
+
+        let err = read_packet(&mut Cursor::new(bytes), Dialect::Source, Direction::ServerToClient, Phase::Command).await.unwrap_err();
+        assert!(matches!(err, RconError::Protocol(_)));
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn read_packet_decodes_unexpected_types_as_unknown() {
+        let bytes = build_packet(1, PacketType::Unknown(7), b"hello", Dialect::Source).unwrap();
+
+        let pkt = read_packet(&mut Cursor::new(bytes), Dialect::Source, Direction::ServerToClient, Phase::Command)
+            .await
+            .unwrap();
+
+        assert_eq!(pkt.packet_type, PacketType::Unknown(7));
+        assert_eq!(pkt.body, b"hello");
+    }
+
+    #[cfg(feature = "async")]
     #[tokio::test]
     async fn read_packet_rejects_bad_terminator() {
         let mut bytes = build_packet(
             1, 
             PacketType::ServerDataAuth, 
-            "hello"
+            b"hello",
+            Dialect::Source,
         ).unwrap();
 
         let n = bytes.len();
@@ -234,7 +378,7 @@ mod tests {
         bytes[n - 1] = 2;
 
         let mut cur = Cursor::new(bytes);
-        let err = read_packet(&mut cur).await.unwrap_err();
+        let err = read_packet(&mut cur, Dialect::Source, Direction::ClientToServer, Phase::Auth).await.unwrap_err();
 
         assert!(matches!(err, RconError::Protocol(_)));
     }
@@ -0,0 +1,552 @@
+//! pipeline.rs
+//!
+//! Concurrent command execution over a single authenticated connection.
+//!
+//! A background task owns the read half of the stream and routes each incoming packet to the
+//! command waiting on its id, so any number of tasks can have commands in flight at once.
+//! Another owns the write half, and writes each command whole, even if the caller stops waiting.
+//! Pipelined clients do not reconnect, once the connection closes every pending and future
+//! command fails.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Cursor};
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+
+use bytes::BytesMut;
+use tokio::io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::{AbortHandle, JoinHandle};
+use tokio::time::timeout;
+
+use crate::client::STALE_ID_LIMIT;
+use crate::client_config::{RconClientConfig, ResponseFraming};
+use crate::packet::{build_packet, read_packet, Packet};
+use crate::rate_limit::{self, RateLimiter};
+use crate::response::ResponseAssembler;
+use crate::trace;
+use crate::{client::RconClient, common::{Direction, PacketType, Phase}, dialect::Dialect, errors::RconError};
+
+#[derive(Debug, Default)]
+struct Waiters {
+    closed: bool,
+    senders: HashMap<i32, mpsc::UnboundedSender<Packet>>,
+    /// The ids of commands which were cancelled or failed before their response was read.
+    stale_ids: VecDeque<i32>,
+}
+
+impl Waiters {
+    /// Stops waiting for the packets with `ids`, discarding them if they arrive later.
+    fn abandon(&mut self, ids: &[i32]) {
+        for id in ids {
+            self.senders.remove(id);
+        }
+        self.stale_ids.extend(ids);
+        while self.stale_ids.len() > STALE_ID_LIMIT {
+            self.stale_ids.pop_front();
+        }
+    }
+
+    /// Fails every pending command, and every command executed from now on.
+    fn close(&mut self) {
+        self.closed = true;
+        self.senders.clear();
+    }
+}
+
+#[derive(Debug)]
+struct Shared<S> {
+    /// The packets of each command, in the order the writer task sends them.
+    writes: mpsc::UnboundedSender<Vec<u8>>,
+    waiters: Arc<StdMutex<Waiters>>,
+    next_id: AtomicI32,
+    client_config: RconClientConfig,
+    rate_limiter: Arc<StdMutex<RateLimiter>>,
+    /// Only the reader task holds on to the sender, so subscribers see the channel close with the connection.
+    events: broadcast::WeakSender<Packet>,
+    reader: JoinHandle<()>,
+    stream: PhantomData<fn() -> S>,
+}
+
+impl<S> Drop for Shared<S> {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// A cloneable handle to a connection shared by concurrent commands.
+/// Created from an authenticated client with `RconClient::into_pipelined`.
+#[derive(Debug)]
+pub struct PipelinedClient<S> {
+    shared: Arc<Shared<S>>,
+}
+
+impl<S> Clone for PipelinedClient<S> {
+    fn clone(&self) -> Self {
+        PipelinedClient { shared: Arc::clone(&self.shared) }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Send + 'static> RconClient<S> {
+    /// Moves the connection onto a background reader task, allowing commands to be executed concurrently.
+    /// The client should already be authenticated.
+    pub fn into_pipelined(mut self) -> PipelinedClient<S> {
+        // Packets partly read or written by the client are finished by the pipelined reader and writer,
+        // packets a cancelled write never started on are not sent at all
+        self.drop_unstarted_packets();
+        // Late responses to a command the client gave up on are discarded, as they were before
+        self.abandon_in_flight();
+        let waiters = Arc::new(StdMutex::new(Waiters { stale_ids: self.stale_ids, ..Default::default() }));
+        let events = self.events.downgrade();
+
+        let (reader, writer) = split(self.stream);
+        let reader = Cursor::new(self.read_buf).chain(reader);
+        let reader = tokio::spawn(read_loop(reader, self.client_config.dialect, Arc::clone(&waiters), self.events));
+        let (writes, rx) = mpsc::unbounded_channel();
+        tokio::spawn(write_loop(writer, self.write_buf, rx, self.client_config.io_timeout, Arc::clone(&waiters), reader.abort_handle()));
+
+        PipelinedClient {
+            shared: Arc::new(Shared {
+                writes,
+                waiters,
+                next_id: AtomicI32::new(self.next_id),
+                client_config: self.client_config,
+                rate_limiter: self.rate_limiter,
+                events,
+                reader,
+                stream: PhantomData,
+            }),
+        }
+    }
+}
+
+async fn read_loop<R: AsyncRead + Unpin>(
+    mut reader: R,
+    dialect: Dialect,
+    waiters: Arc<StdMutex<Waiters>>,
+    events: broadcast::Sender<Packet>,
+) {
+    loop {
+        match read_packet(&mut reader, dialect, Direction::ServerToClient, Phase::Command).await {
+            Ok(pkt) => {
+                let waiters = waiters.lock().unwrap();
+                match waiters.senders.get(&pkt.id) {
+                    Some(tx) => { let _ = tx.send(pkt); },
+                    None if waiters.stale_ids.contains(&pkt.id) => {
+                        log::debug!("Discarding packet with id {:?} of an abandoned command", pkt.id);
+                    },
+                    None if events.receiver_count() > 0 => {
+                        log::debug!("Publishing packet with id {:?} with no pending command", pkt.id);
+                        let _ = events.send(pkt);
+                    },
+                    None => log::debug!("Received packet with id {:?} with no pending command, ignoring", pkt.id),
+                }
+            },
+            Err(e) => {
+                log::warn!("Pipelined connection closed: {}", e);
+                waiters.lock().unwrap().close();
+                return;
+            }
+        }
+    }
+}
+
+/// Writes the queued packets, starting with `buf`, until every handle to the client is dropped.
+/// Bytes are only removed from `buf` once written. A write which fails or takes longer than `io_timeout` closes the connection, as the server
+/// may have been left with part of a packet.
+async fn write_loop<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    mut buf: BytesMut,
+    mut writes: mpsc::UnboundedReceiver<Vec<u8>>,
+    io_timeout: Duration,
+    waiters: Arc<StdMutex<Waiters>>,
+    reader: AbortHandle,
+) {
+    loop {
+        if buf.is_empty() {
+            match writes.recv().await {
+                Some(packets) => buf.extend_from_slice(&packets),
+                None => return,
+            }
+        }
+        while let Ok(packets) = writes.try_recv() {
+            buf.extend_from_slice(&packets);
+        }
+
+        let write = async {
+            while !buf.is_empty() {
+                let n = writer.write(&buf).await?;
+                if n == 0 {
+                    return Err(io::Error::from(io::ErrorKind::WriteZero));
+                }
+                let _ = buf.split_to(n);
+            }
+            writer.flush().await
+        };
+        let err = match timeout(io_timeout, write).await {
+            Ok(Ok(())) => continue,
+            Ok(Err(e)) => RconError::Io(e),
+            Err(_) => RconError::Timeout,
+        };
+
+        log::warn!("Pipelined connection closed, could not write: {}", err);
+        waiters.lock().unwrap().close();
+        reader.abort();
+        return;
+    }
+}
+
+/// Removes the waiters for a command when it completes or is dropped.
+/// The ids of a command dropped before completing are marked stale.
+struct Pending<'a> {
+    waiters: &'a StdMutex<Waiters>,
+    ids: Vec<i32>,
+    done: bool,
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        let mut waiters = self.waiters.lock().unwrap();
+        if self.done {
+            for id in &self.ids {
+                waiters.senders.remove(id);
+            }
+        } else {
+            waiters.abandon(&self.ids);
+        }
+    }
+}
+
+impl<S> PipelinedClient<S> {
+    /// Executes a command, waiting for its response while other commands may be in flight.
+    /// The response is decoded with the configured `ResponseEncoding`.
+    pub async fn execute(&self, command: &str) -> Result<String, RconError> {
+        let response = self.execute_bytes(command.as_bytes()).await?;
+        self.shared.client_config.response_encoding.decode(response)
+    }
+
+    /// Like `execute`, returning the raw bytes of the response.
+    /// Commands too long for one packet are split as `RconClient::execute_bytes` splits them.
+    pub async fn execute_bytes(&self, command: &[u8]) -> Result<Vec<u8>, RconError> {
+        let mut results = Vec::new();
+        for chunk in self.shared.client_config.dialect.split_command(command)? {
+            results.extend(self.execute_packet(chunk).await?);
+        }
+        Ok(results)
+    }
+
+    async fn execute_packet(&self, command: &[u8]) -> Result<Vec<u8>, RconError> {
+        let config = &self.shared.client_config;
+        let framing = config.response_framing;
+        rate_limit::acquire(&self.shared.rate_limiter, command).await?;
+
+        let cmd_id = self.alloc_id();
+        let mirror_id = (framing == ResponseFraming::Mirror).then(|| self.alloc_id());
+        trace::command_sent(cmd_id, command.len());
+
+        let mut buf = build_packet(cmd_id, PacketType::ServerDataExecCommand, command, config.dialect)?;
+        if let Some(id) = mirror_id {
+            buf.extend(build_packet(id, PacketType::ServerDataResponseValue, b"", config.dialect)?);
+        }
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut pending = self.register(cmd_id, mirror_id, tx)?;
+        self.shared.writes.send(buf)
+            .map_err(|_| RconError::ClientError("Connection closed".to_string()))?;
+
+        let mut response = ResponseAssembler::new(framing, config.dialect, cmd_id, mirror_id);
+        let wait = if framing == ResponseFraming::IdleTimeout { config.idle_timeout } else { config.io_timeout };
+
+        loop {
+            let pkt = match timeout(wait, rx.recv()).await {
+                Ok(Some(pkt)) => pkt,
+                Ok(None) => return Err(RconError::ClientError("Connection closed".to_string())),
+                Err(_) if framing == ResponseFraming::IdleTimeout => {
+                    response.idle();
+                    break;
+                },
+                Err(_) => return Err(RconError::Timeout),
+            };
+
+            if response.push(pkt) {
+                break;
+            }
+        }
+
+        pending.done = true;
+        Ok(response.finish())
+    }
+
+    /// Subscribes to packets which match no pending command, eg: chat and kill events pushed by the server.
+    /// These are delivered as soon as the background reader receives them.
+    /// Once the connection closes, receivers see `RecvError::Closed`.
+    pub fn subscribe(&self) -> broadcast::Receiver<Packet> {
+        match self.shared.events.upgrade() {
+            Some(events) => events.subscribe(),
+            None => broadcast::channel(1).1,
+        }
+    }
+
+    /// Whether the background reader has stopped, after which every command fails.
+    pub fn is_closed(&self) -> bool {
+        self.shared.waiters.lock().unwrap().closed
+    }
+
+    fn alloc_id(&self) -> i32 {
+        self.shared.next_id
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |id| {
+                Some(if id >= i32::MAX - 10 { 1 } else { id + 1 })
+            })
+            .unwrap()
+    }
+
+    fn register(
+        &self,
+        cmd_id: i32,
+        mirror_id: Option<i32>,
+        tx: mpsc::UnboundedSender<Packet>,
+    ) -> Result<Pending<'_>, RconError> {
+        let mut waiters = self.shared.waiters.lock().unwrap();
+        if waiters.closed {
+            return Err(RconError::ClientError("Connection closed".to_string()));
+        }
+
+        let ids: Vec<i32> = std::iter::once(cmd_id).chain(mirror_id).collect();
+        for id in &ids {
+            waiters.senders.insert(*id, tx.clone());
+        }
+
+        Ok(Pending { waiters: &self.shared.waiters, ids, done: false })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::test_support::{self, echo_connector, MAX_BUFFER_SIZE};
+    use tokio::io::duplex;
+
+    fn config() -> RconClientConfig {
+        test_support::config()
+            .io_timeout(Duration::from_secs(1))
+            .idle_timeout(Duration::from_millis(100))
+            .response_framing(ResponseFraming::Mirror)
+    }
+
+    #[tokio::test]
+    async fn execute_runs_commands_concurrently() {
+        let client = RconClient::connect_with(config(), echo_connector(Arc::default())).await.unwrap().into_pipelined();
+
+        let tasks: Vec<_> = (0..12)
+            .map(|i| {
+                let client = client.clone();
+                tokio::spawn(async move { (i, client.execute(&format!("cmd {}", i)).await.unwrap()) })
+            })
+            .collect();
+
+        for task in tasks {
+            let (i, out) = task.await.unwrap();
+            assert_eq!(out, format!("cmd {}", i));
+        }
+    }
+
+    #[tokio::test]
+    async fn execute_shares_rate_limit_between_handles() {
+        let config = config().rate_limit(crate::rate_limit::RateLimit::new(1, 10.0));
+        let client = RconClient::connect_with(config, echo_connector(Arc::default())).await.unwrap().into_pipelined();
+
+        let other = client.clone();
+        let start = std::time::Instant::now();
+        let (a, b) = tokio::join!(client.execute("a"), other.execute("b"));
+
+        assert_eq!((a.unwrap(), b.unwrap()), ("a".to_string(), "b".to_string()));
+        assert!(start.elapsed() >= Duration::from_millis(90), "{:?}", start.elapsed());
+    }
+
+    #[tokio::test]
+    async fn execute_splits_long_commands() {
+        let client = RconClient::connect_with(config(), echo_connector(Arc::default())).await.unwrap().into_pipelined();
+
+        let command = "a".repeat(600);
+        assert_eq!(client.execute(&command).await.unwrap(), command);
+    }
+
+    #[tokio::test]
+    async fn execute_routes_out_of_order_responses() {
+        let (client_stream, server_stream) = duplex(MAX_BUFFER_SIZE);
+        let client = RconClient::new(client_stream)
+            .with_client_config(RconClientConfig {
+                response_framing: ResponseFraming::EmptyPacket,
+                ..config()
+            })
+            .into_pipelined();
+
+        let server = tokio::spawn(async move {
+            let mut server_client = RconClient::new(server_stream).with_client_config(config());
+            let first = server_client.read_request().await.unwrap();
+            let second = server_client.read_request().await.unwrap();
+
+            for pkt in [&second, &first] {
+                server_client = server_client.with_next_id(pkt.id);
+                server_client.write_packet(PacketType::ServerDataResponseValue, &pkt.body).await.unwrap();
+                server_client = server_client.with_next_id(pkt.id);
+                server_client.write_packet(PacketType::ServerDataResponseValue, b"").await.unwrap();
+            }
+        });
+
+        let first = client.execute("first");
+        let second = async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            client.execute("second").await
+        };
+        let (first, second) = tokio::join!(first, second);
+
+        assert_eq!(first.unwrap(), "first");
+        assert_eq!(second.unwrap(), "second");
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn subscribers_receive_packets_pushed_between_commands() {
+        let (client_stream, server_stream) = duplex(MAX_BUFFER_SIZE);
+        let client = RconClient::new(client_stream).with_client_config(config()).into_pipelined();
+        let mut events = client.subscribe();
+
+        let mut server_client = RconClient::new(server_stream).with_client_config(config()).with_next_id(42);
+        server_client.write_packet(PacketType::ServerDataResponseValue, b"[Kill] a killed b").await.unwrap();
+
+        let event = timeout(Duration::from_secs(1), events.recv()).await.unwrap().unwrap();
+        assert_eq!(event.id, 42);
+        assert_eq!(event.body, b"[Kill] a killed b");
+    }
+
+    #[tokio::test]
+    async fn execute_fails_once_connection_closes() {
+        let (client_stream, server_stream) = duplex(MAX_BUFFER_SIZE);
+        let client = RconClient::new(client_stream).with_client_config(config()).into_pipelined();
+        drop(server_stream);
+
+        let res = client.execute("cmd").await;
+        assert!(res.is_err());
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(client.is_closed());
+        assert!(client.execute("cmd").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn cancelled_execute_does_not_affect_later_commands() {
+        let client = RconClient::connect_with(config(), echo_connector(Arc::default())).await.unwrap().into_pipelined();
+        let mut events = client.subscribe();
+
+        let cancelled = timeout(Duration::from_millis(10), client.execute("slow")).await;
+        assert!(cancelled.is_err());
+
+        assert_eq!(client.execute("next").await.unwrap(), "next");
+        assert!(client.shared.waiters.lock().unwrap().senders.is_empty());
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn late_responses_to_commands_abandoned_before_pipelining_are_not_published() {
+        let (client_stream, server_stream) = duplex(MAX_BUFFER_SIZE);
+        let mut client = RconClient::new(client_stream).with_client_config(config());
+        client.stale_ids.push_back(7);
+        client.in_flight = Some((8, Some(9)));
+        let client = client.into_pipelined();
+        let mut events = client.subscribe();
+
+        let mut server_client = RconClient::new(server_stream).with_client_config(config());
+        for id in [7, 8, 9, 42] {
+            server_client = server_client.with_next_id(id);
+            server_client.write_packet(PacketType::ServerDataResponseValue, b"").await.unwrap();
+        }
+
+        let event = timeout(Duration::from_secs(1), events.recv()).await.unwrap().unwrap();
+        assert_eq!(event.id, 42);
+    }
+
+    #[tokio::test]
+    async fn subscribers_see_connection_close() {
+        let (client_stream, server_stream) = duplex(MAX_BUFFER_SIZE);
+        let client = RconClient::new(client_stream).with_client_config(config()).into_pipelined();
+        let mut events = client.subscribe();
+        drop(server_stream);
+
+        let res = timeout(Duration::from_secs(1), events.recv()).await.unwrap();
+        assert!(matches!(res, Err(broadcast::error::RecvError::Closed)), "{:?}", res);
+        assert!(matches!(client.subscribe().try_recv(), Err(broadcast::error::TryRecvError::Closed)));
+    }
+
+    #[tokio::test]
+    async fn cancelled_execute_still_writes_whole_packets() {
+        // The server is not reading yet, so only part of the command fits
+        let (client_stream, server_stream) = duplex(32);
+        let client = RconClient::new(client_stream).with_client_config(config()).into_pipelined();
+
+        let long = "a".repeat(400);
+        assert!(timeout(Duration::from_millis(20), client.execute(&long)).await.is_err());
+
+        let server = tokio::spawn(async move {
+            let mut server_client = RconClient::new(server_stream).with_client_config(config());
+            let cancelled = server_client.read_request().await.unwrap();
+            let _mirror = server_client.read_request().await.unwrap();
+            let next = server_client.read_request().await.unwrap();
+            let mirror = server_client.read_request().await.unwrap();
+
+            server_client = server_client.with_next_id(next.id);
+            server_client.write_packet(PacketType::ServerDataResponseValue, &next.body).await.unwrap();
+            server_client = server_client.with_next_id(mirror.id);
+            server_client.write_packet(PacketType::ServerDataResponseValue, b"").await.unwrap();
+            cancelled.body
+        });
+
+        assert_eq!(client.execute("next").await.unwrap(), "next");
+        assert_eq!(server.await.unwrap(), long.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn into_pipelined_finishes_partial_packet_and_drops_unstarted_ones() {
+        let (client_stream, server_stream) = duplex(8);
+        let mut client = RconClient::new(client_stream).with_client_config(config());
+        client.queue_packet(PacketType::ServerDataExecCommand, b"first").unwrap();
+        client.queue_packet(PacketType::ServerDataExecCommand, b"never sent").unwrap();
+        let cancelled = timeout(Duration::from_millis(20), client.flush_packets(Duration::from_secs(1), Phase::Command)).await;
+        assert!(cancelled.is_err());
+        let client = client.into_pipelined();
+
+        let server = tokio::spawn(async move {
+            let mut server_client = RconClient::new(server_stream).with_client_config(config());
+            let first = server_client.read_request().await.unwrap();
+            let next = server_client.read_request().await.unwrap();
+            let mirror = server_client.read_request().await.unwrap();
+
+            server_client = server_client.with_next_id(next.id);
+            server_client.write_packet(PacketType::ServerDataResponseValue, &next.body).await.unwrap();
+            server_client = server_client.with_next_id(mirror.id);
+            server_client.write_packet(PacketType::ServerDataResponseValue, b"").await.unwrap();
+            first.body
+        });
+
+        assert_eq!(client.execute("next").await.unwrap(), "next");
+        assert_eq!(server.await.unwrap(), b"first");
+    }
+
+    #[tokio::test]
+    async fn execute_closes_connection_when_write_times_out() {
+        let (client_stream, _server_stream) = duplex(32);
+        let client = RconClient::new(client_stream)
+            .with_client_config(RconClientConfig { io_timeout: Duration::from_millis(50), ..config() })
+            .into_pipelined();
+
+        assert!(client.execute(&"a".repeat(400)).await.is_err());
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(client.is_closed());
+        assert!(client.execute("cmd").await.is_err());
+    }
+}
@@ -0,0 +1,397 @@
+//! pool.rs
+//!
+//! A pool of authenticated connections built from a single `RconClientConfig`.
+//!
+//! Connections are handed out as `PooledClient` guards and returned to the pool when dropped,
+//! unless a command on them was cancelled or failed partway. Idle connections are validated with
+//! a cheap command before reuse, and dead connections are replaced through the usual
+//! `connect`/`authenticate` path.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::client_config::RconClientConfig;
+use crate::net::NetStream;
+use crate::rate_limit::RateLimiter;
+use crate::{client::RconClient, errors::RconError};
+
+const DEFAULT_MAX_SIZE: usize = 4;
+const DEFAULT_HEALTH_CHECK_AFTER: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub struct RconPoolConfig {
+    pub max_size: usize,
+    pub health_check_command: Option<String>,
+    pub health_check_after: Duration,
+}
+
+impl Default for RconPoolConfig {
+    fn default() -> Self {
+        Self {
+            max_size: DEFAULT_MAX_SIZE,
+            health_check_command: Some(String::new()),
+            health_check_after: DEFAULT_HEALTH_CHECK_AFTER,
+        }
+    }
+}
+
+impl RconPoolConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The maximum number of connections the pool will open at once.
+    /// Callers of `RconPool::get` wait for a connection to be returned once this is reached.
+    /// A pool always allows at least one connection.
+    pub fn max_size(mut self, v: usize) -> Self { self.max_size = v; self }
+
+    /// The command sent to validate an idle connection before handing it out again.
+    /// Any response counts as healthy, an error causes the connection to be replaced.
+    ///
+    /// Defaults to an empty command. `None` disables validation.
+    pub fn health_check_command(mut self, v: Option<String>) -> Self { self.health_check_command = v; self }
+
+    /// How long a connection must have been idle before it is health checked.
+    /// Connections used more recently than this are handed out straight away.
+    ///
+    /// Defaults to 5 seconds.
+    pub fn health_check_after(mut self, v: Duration) -> Self { self.health_check_after = v; self }
+}
+
+#[derive(Debug)]
+struct PoolInner {
+    client_config: RconClientConfig,
+    pool_config: RconPoolConfig,
+    idle: Mutex<Vec<RconClient<NetStream>>>,
+    permits: Arc<Semaphore>,
+    /// Shared by every connection, so `rate_limit` applies to the pool as a whole.
+    rate_limiter: Arc<Mutex<RateLimiter>>,
+}
+
+/// A cloneable handle to a pool of connections.
+#[derive(Debug, Clone)]
+pub struct RconPool {
+    inner: Arc<PoolInner>,
+}
+
+impl RconPool {
+    pub fn new(client_config: RconClientConfig, mut pool_config: RconPoolConfig) -> Self {
+        if pool_config.max_size == 0 {
+            log::warn!("Pool max_size of 0 would never hand out a connection, using 1");
+            pool_config.max_size = 1;
+        }
+        RconPool {
+            inner: Arc::new(PoolInner {
+                rate_limiter: Arc::new(Mutex::new(RateLimiter::new(client_config.rate_limit.as_ref()))),
+                client_config,
+                permits: Arc::new(Semaphore::new(pool_config.max_size)),
+                pool_config,
+                idle: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Checks out a connection, waiting for one to be returned if the pool is at `max_size`.
+    ///
+    /// Idle connections are reused after passing the health check,
+    /// otherwise a new connection is opened and authenticated.
+    pub async fn get(&self) -> Result<PooledClient, RconError> {
+        let permit = Arc::clone(&self.inner.permits)
+            .acquire_owned()
+            .await
+            .map_err(|_| RconError::ClientError("Pool closed".to_string()))?;
+
+        loop {
+            let idle = self.inner.idle.lock().unwrap().pop();
+            let Some(mut client) = idle else { break };
+
+            if self.is_healthy(&mut client).await {
+                log::debug!("Reusing pooled connection");
+                return Ok(PooledClient::new(client, self.clone(), permit));
+            }
+            log::debug!("Pooled connection failed health check, discarding");
+        }
+
+        log::debug!("Opening new pooled connection");
+        let client = RconClient::connect(self.inner.client_config.clone()).await?
+            .with_rate_limiter(Arc::clone(&self.inner.rate_limiter));
+        Ok(PooledClient::new(client, self.clone(), permit))
+    }
+
+    /// Closes the idle connections, and fails every current and future call to `get`.
+    /// Connections which are checked out are closed once they are dropped.
+    pub fn close(&self) {
+        self.inner.permits.close();
+        self.inner.idle.lock().unwrap().clear();
+    }
+
+    /// The number of connections currently waiting in the pool.
+    pub fn idle_count(&self) -> usize {
+        self.inner.idle.lock().unwrap().len()
+    }
+
+    /// The number of connections which may still be checked out before `get` waits.
+    pub fn available(&self) -> usize {
+        self.inner.permits.available_permits()
+    }
+
+    async fn is_healthy(&self, client: &mut RconClient<NetStream>) -> bool {
+        if client.idle_for() < self.inner.pool_config.health_check_after {
+            return true;
+        }
+        match &self.inner.pool_config.health_check_command {
+            Some(command) => {
+                // Counted against the pool wide rate limit like any other command
+                client.throttle(command.as_bytes()).await.is_ok() && client._execute(command).await.is_ok()
+            },
+            None => true,
+        }
+    }
+
+    fn release(&self, client: RconClient<NetStream>) {
+        if self.inner.permits.is_closed() {
+            log::debug!("Pool closed, closing returned connection");
+            return;
+        }
+        if client.is_interrupted() {
+            log::debug!("Pooled connection was left partway through a command, discarding");
+            return;
+        }
+        self.inner.idle.lock().unwrap().push(client);
+    }
+}
+
+/// A connection checked out of an `RconPool`, returned to the pool when dropped.
+#[derive(Debug)]
+pub struct PooledClient {
+    client: Option<RconClient<NetStream>>,
+    pool: RconPool,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl PooledClient {
+    fn new(client: RconClient<NetStream>, pool: RconPool, permit: OwnedSemaphorePermit) -> Self {
+        PooledClient {
+            client: Some(client),
+            pool,
+            _permit: permit,
+        }
+    }
+
+    /// Closes the connection instead of returning it to the pool,
+    /// eg: when the caller knows it is no longer usable.
+    pub fn discard(mut self) {
+        self.client.take();
+    }
+}
+
+impl Deref for PooledClient {
+    type Target = RconClient<NetStream>;
+
+    fn deref(&self) -> &Self::Target {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledClient {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            self.pool.release(client);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+    use crate::rate_limit::RateLimit;
+    use crate::server::RconServer;
+    use crate::test_support::{self, Counted, Echo};
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    struct TestServer {
+        config: RconClientConfig,
+        accepted: Arc<AtomicUsize>,
+        commands: Arc<AtomicUsize>,
+        connections: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    }
+
+    async fn start_server() -> TestServer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let commands = Arc::new(AtomicUsize::new(0));
+        let connections = Arc::new(Mutex::new(Vec::new()));
+
+        let (accepted_count, handles) = (Arc::clone(&accepted), Arc::clone(&connections));
+        let echo = Counted(Arc::clone(&commands), Echo);
+        tokio::spawn(async move {
+            let server = RconServer::new("pw".to_string(), echo);
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                accepted_count.fetch_add(1, Ordering::SeqCst);
+                let server = server.clone();
+                let handle = tokio::spawn(async move { let _ = server.serve(stream).await; });
+                handles.lock().unwrap().push(handle);
+            }
+        });
+
+        let config = RconClientConfig { address: addr.ip().to_string(), port: addr.port(), ..test_support::config() };
+        TestServer { config, accepted, commands, connections }
+    }
+
+    #[tokio::test]
+    async fn get_reuses_returned_connections() {
+        let server = start_server().await;
+        let pool = RconPool::new(server.config.clone(), RconPoolConfig::new());
+
+        {
+            let mut client = pool.get().await.unwrap();
+            assert_eq!(client.execute("first").await.unwrap(), "first");
+        }
+        assert_eq!(pool.idle_count(), 1);
+
+        let mut client = pool.get().await.unwrap();
+        assert_eq!(client.execute("second").await.unwrap(), "second");
+        assert_eq!(server.accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn get_waits_when_pool_is_exhausted() {
+        let server = start_server().await;
+        let pool = RconPool::new(server.config.clone(), RconPoolConfig::new().max_size(2));
+
+        let first = pool.get().await.unwrap();
+        let _second = pool.get().await.unwrap();
+        assert_eq!(pool.available(), 0);
+
+        let third = tokio::time::timeout(Duration::from_millis(50), pool.get()).await;
+        assert!(third.is_err());
+
+        drop(first);
+        let third = tokio::time::timeout(Duration::from_millis(50), pool.get()).await;
+        assert!(third.is_ok());
+        assert_eq!(server.accepted.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn get_replaces_dead_connections() {
+        let server = start_server().await;
+        let pool = RconPool::new(server.config.clone(), RconPoolConfig::new().health_check_after(Duration::ZERO));
+
+        drop(pool.get().await.unwrap());
+        for handle in server.connections.lock().unwrap().iter() {
+            handle.abort();
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let mut client = pool.get().await.unwrap();
+        assert_eq!(client.execute("alive").await.unwrap(), "alive");
+        assert_eq!(server.accepted.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn connections_share_rate_limit() {
+        let server = start_server().await;
+        let config = server.config.clone().rate_limit(RateLimit::new(1, 10.0));
+        let pool = RconPool::new(config, RconPoolConfig::new());
+
+        let mut first = pool.get().await.unwrap();
+        let mut second = pool.get().await.unwrap();
+        let start = std::time::Instant::now();
+        assert_eq!(first.execute("a").await.unwrap(), "a");
+        assert_eq!(second.execute("b").await.unwrap(), "b");
+
+        assert!(start.elapsed() >= Duration::from_millis(90), "{:?}", start.elapsed());
+    }
+
+    #[tokio::test]
+    async fn get_skips_health_check_for_recently_used_connections() {
+        let server = start_server().await;
+        let pool = RconPool::new(server.config.clone(), RconPoolConfig::new().health_check_after(Duration::from_secs(60)));
+
+        pool.get().await.unwrap().execute("first").await.unwrap();
+        pool.get().await.unwrap().execute("second").await.unwrap();
+
+        assert_eq!(server.commands.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn cancelled_execute_does_not_return_connection() {
+        let server = start_server().await;
+        let pool = RconPool::new(server.config.clone(), RconPoolConfig::new());
+
+        let mut client = pool.get().await.unwrap();
+        // Responses are only complete once the server goes quiet for `idle_timeout`
+        assert!(tokio::time::timeout(Duration::from_millis(5), client.execute("first")).await.is_err());
+        drop(client);
+
+        assert_eq!(pool.idle_count(), 0);
+        assert_eq!(pool.available(), RconPoolConfig::new().max_size);
+    }
+
+    #[tokio::test]
+    async fn health_checks_count_against_rate_limit() {
+        let server = start_server().await;
+        let config = server.config.clone().rate_limit(RateLimit::new(1, 10.0));
+        let pool = RconPool::new(config, RconPoolConfig::new().health_check_after(Duration::ZERO));
+
+        pool.get().await.unwrap().execute("a").await.unwrap();
+        let start = std::time::Instant::now();
+        drop(pool.get().await.unwrap());
+
+        assert!(start.elapsed() >= Duration::from_millis(90), "{:?}", start.elapsed());
+    }
+
+    #[tokio::test]
+    async fn zero_max_size_still_allows_one_connection() {
+        let server = start_server().await;
+        let pool = RconPool::new(server.config.clone(), RconPoolConfig::new().max_size(0));
+
+        let get = tokio::time::timeout(Duration::from_secs(1), pool.get()).await;
+        assert!(get.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn close_fails_waiting_and_later_gets() {
+        let server = start_server().await;
+        let pool = RconPool::new(server.config.clone(), RconPoolConfig::new().max_size(1));
+        let client = pool.get().await.unwrap();
+
+        let waiting = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.get().await.map(drop) }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        pool.close();
+
+        assert!(matches!(waiting.await.unwrap(), Err(RconError::ClientError(_))));
+        assert!(pool.get().await.is_err());
+        drop(client);
+        assert_eq!(pool.idle_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn discard_does_not_return_connection() {
+        let server = start_server().await;
+        let pool = RconPool::new(server.config.clone(), RconPoolConfig::new());
+
+        pool.get().await.unwrap().discard();
+
+        assert_eq!(pool.idle_count(), 0);
+        assert_eq!(pool.available(), RconPoolConfig::new().max_size);
+    }
+}
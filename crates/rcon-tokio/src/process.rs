@@ -0,0 +1,163 @@
+//! process.rs
+//!
+//! Runs the protocol over the stdin and stdout of a spawned command, like OpenSSH's `ProxyCommand`.
+//! This tunnels through anything which can forward a byte stream, eg: `ssh bastion nc %h %p`,
+//! without the crate implementing those protocols itself.
+
+use std::io;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+
+use crate::client_config::RconClientConfig;
+use crate::connect::{ConnectFuture, Connector};
+use crate::{client::RconClient, errors::RconError};
+
+/// The stdin and stdout of a spawned command. The command is killed once the stream is dropped.
+#[derive(Debug)]
+pub struct ProcessStream {
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+    child: Child,
+}
+
+impl ProcessStream {
+    /// The OS assigned id of the command, while it is still running.
+    pub fn id(&self) -> Option<u32> {
+        self.child.id()
+    }
+}
+
+impl AsyncRead for ProcessStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdout).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ProcessStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stdin).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdin).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdin).poll_shutdown(cx)
+    }
+}
+
+/// Spawns a shell command for each connection, and speaks the protocol over its stdin and stdout.
+///
+/// `%h` and `%p` in the command are replaced with the config's `address` and `port`, and `%%` with `%`.
+/// The substituted values are not quoted, so the address should come from a trusted source.
+/// The command's stderr is inherited, so errors from eg: ssh are still shown.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessConnector {
+    pub command: String,
+}
+
+impl ProcessConnector {
+    pub fn new(command: String) -> Self {
+        Self { command }
+    }
+
+    fn expand(&self, config: &RconClientConfig) -> String {
+        let mut expanded = String::with_capacity(self.command.len());
+        let mut chars = self.command.chars();
+        while let Some(c) = chars.next() {
+            match (c, chars.clone().next()) {
+                ('%', Some('h')) => { expanded.push_str(&config.address); chars.next(); },
+                ('%', Some('p')) => { expanded.push_str(&config.port.to_string()); chars.next(); },
+                ('%', Some('%')) => { expanded.push('%'); chars.next(); },
+                (c, _) => expanded.push(c),
+            }
+        }
+        expanded
+    }
+}
+
+impl Connector<ProcessStream> for ProcessConnector {
+    fn connect<'a>(&'a self, config: &'a RconClientConfig) -> ConnectFuture<'a, ProcessStream> {
+        Box::pin(async move {
+            let command = self.expand(config);
+            log::debug!("Spawning proxy command: {}", command);
+
+            let mut child = shell(&command)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::inherit())
+                .kill_on_drop(true)
+                .spawn()
+                .map_err(|e| RconError::ClientError(format!("Failed to spawn proxy command {:?}: {}", command, e)))?;
+
+            let stdin = child.stdin.take().expect("stdin is piped");
+            let stdout = child.stdout.take().expect("stdout is piped");
+            Ok(ProcessStream { stdin, stdout, child })
+        })
+    }
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+impl RconClient<ProcessStream> {
+    /// Spawns `command` with `ProcessConnector`, and authenticates over its stdin and stdout.
+    pub async fn connect_process(client_config: RconClientConfig, command: String) -> Result<Self, RconError> {
+        RconClient::connect_with(client_config, ProcessConnector::new(command)).await
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    fn config() -> RconClientConfig {
+        RconClientConfig::new("game.internal".to_string(), 27015, "pw".to_string())
+            .io_timeout(Duration::from_secs(1))
+    }
+
+    #[test]
+    fn expand_substitutes_host_and_port() {
+        let connector = ProcessConnector::new("ssh bastion nc %h %p # 100%%".to_string());
+
+        assert_eq!(connector.expand(&config()), "ssh bastion nc game.internal 27015 # 100%");
+    }
+
+    #[tokio::test]
+    async fn stream_reads_stdout_and_writes_stdin() {
+        let mut stream = ProcessConnector::new("cat".to_string()).connect(&config()).await.unwrap();
+
+        stream.write_all(b"\x0a\x00\x00\x00ping").await.unwrap();
+        let mut buf = [0u8; 8];
+        stream.read_exact(&mut buf).await.unwrap();
+
+        assert_eq!(&buf, b"\x0a\x00\x00\x00ping");
+    }
+
+    #[tokio::test]
+    async fn connect_fails_when_command_exits() {
+        let res = RconClient::connect_process(config(), "echo %h:%p >&2; exit 1".to_string()).await;
+
+        assert!(matches!(res, Err(RconError::Io(_))), "{:?}", res);
+    }
+}
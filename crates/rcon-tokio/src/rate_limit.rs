@@ -0,0 +1,252 @@
+//! rate_limit.rs
+//!
+//! Client side throttling of commands, for hosted servers which kick or ban clients that send too many
+//! commands per second, eg: during bulk whitelist imports.
+//!
+//! Each limit is a token bucket: up to `burst` commands are sent straight away, then one more every
+//! `1 / per_second` seconds. Waiting for a token happens before the command is written, so it does not
+//! count against `io_timeout`, and a wait which is cancelled does not use up a token.
+//! The connections of a pool, and the handles of a pipelined client, share their client's buckets.
+
+#[cfg(feature = "async")]
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::errors::RconError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_second: f64,
+    pub prefix_limits: Vec<(String, RateLimit)>,
+}
+
+impl RateLimit {
+    /// Allows bursts of up to `burst` commands, refilled at `per_second` commands per second.
+    /// `per_second` must be positive, otherwise commands fail with `ClientError` instead of waiting forever.
+    pub fn new(burst: u32, per_second: f64) -> Self {
+        Self { burst, per_second, prefix_limits: Vec::new() }
+    }
+
+    /// Gives commands starting with `prefix` their own bucket with `limit`, instead of this one, eg:
+    /// `whitelist add`. The longest matching prefix wins. Prefix limits of `limit` itself are ignored.
+    pub fn prefix(mut self, prefix: &str, limit: RateLimit) -> Self {
+        self.prefix_limits.push((prefix.to_string(), limit));
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Bucket {
+    burst: f64,
+    per_second: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(limit: &RateLimit, now: Instant) -> Self {
+        let burst = limit.burst.max(1) as f64;
+        Self { burst, per_second: limit.per_second, tokens: burst, updated: now }
+    }
+
+    /// Takes a token, or returns how long until one is available.
+    fn try_take(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        if self.per_second > 0.0 {
+            self.tokens = (self.tokens + elapsed * self.per_second).min(self.burst);
+        }
+        self.updated = self.updated.max(now);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::try_from_secs_f64((1.0 - self.tokens) / self.per_second).unwrap_or(Duration::MAX))
+    }
+}
+
+/// The buckets of a client's `RateLimit`, with the default bucket under the empty prefix.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RateLimiter {
+    buckets: Vec<(String, Bucket)>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limit: Option<&RateLimit>) -> Self {
+        let Some(limit) = limit else {
+            return Self::default();
+        };
+
+        let now = Instant::now();
+        let mut buckets = vec![(String::new(), Bucket::new(limit, now))];
+        buckets.extend(limit.prefix_limits.iter().map(|(prefix, l)| (prefix.clone(), Bucket::new(l, now))));
+        // Longest prefix first, so the first match is the most specific one
+        buckets.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        Self { buckets }
+    }
+
+    /// Fails if a bucket would never refill, so callers do not wait forever for a token.
+    pub(crate) fn validate(&self) -> Result<(), RconError> {
+        match self.buckets.iter().find(|(_, bucket)| bucket.per_second.is_nan() || bucket.per_second <= 0.0) {
+            Some((prefix, bucket)) => Err(RconError::ClientError(format!(
+                "Rate limit for prefix {:?} must allow a positive number of commands per second, got {}", prefix, bucket.per_second
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Takes a token for `command`, or returns how long until one is available.
+    pub(crate) fn try_acquire(&mut self, command: &[u8], now: Instant) -> Result<(), Duration> {
+        let command = command.trim_ascii_start();
+        match self.buckets.iter_mut().find(|(prefix, _)| command.starts_with(prefix.as_bytes())) {
+            Some((_, bucket)) => bucket.try_take(now),
+            None => Ok(()),
+        }
+    }
+
+    /// Blocks the thread until a token for `command` is available, and takes it.
+    #[cfg(feature = "blocking")]
+    pub(crate) fn acquire_blocking(&mut self, command: &[u8]) -> Result<(), RconError> {
+        self.validate()?;
+        while let Err(wait) = self.try_acquire(command, Instant::now()) {
+            log::debug!("Rate limited, waiting {:?} before sending command", wait);
+            std::thread::sleep(wait);
+        }
+        Ok(())
+    }
+}
+
+/// Waits until a token for `command` is available from a limiter shared between clients, and takes it.
+/// Dropping the future while it waits leaves the bucket untouched.
+#[cfg(feature = "async")]
+pub(crate) async fn acquire(limiter: &Mutex<RateLimiter>, command: &[u8]) -> Result<(), RconError> {
+    limiter.lock().unwrap().validate()?;
+    loop {
+        let res = limiter.lock().unwrap().try_acquire(command, Instant::now());
+        let Err(wait) = res else {
+            return Ok(());
+        };
+        log::debug!("Rate limited, waiting {:?} before sending command", wait);
+        tokio::time::sleep(wait).await;
+    }
+}
+
+#[cfg(feature = "async")]
+impl<S> crate::client::RconClient<S> {
+    /// Waits until a token for `command` is available, and takes it.
+    pub(crate) async fn throttle(&mut self, command: &[u8]) -> Result<(), RconError> {
+        acquire(&self.rate_limiter, command).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn bucket_allows_burst_then_refills_at_rate() {
+        let mut limiter = RateLimiter::new(Some(&RateLimit::new(2, 10.0)));
+        let start = Instant::now();
+
+        assert_eq!(limiter.try_acquire(b"status", start), Ok(()));
+        assert_eq!(limiter.try_acquire(b"status", start), Ok(()));
+        let wait = limiter.try_acquire(b"status", start).unwrap_err();
+        assert!(wait > 99 * MS && wait <= 100 * MS, "{:?}", wait);
+
+        assert_eq!(limiter.try_acquire(b"status", start + 101 * MS), Ok(()));
+        assert!(limiter.try_acquire(b"status", start + 101 * MS).is_err());
+        // Idle time refills at most `burst` tokens
+        assert_eq!(limiter.try_acquire(b"status", start + 10_000 * MS), Ok(()));
+        assert_eq!(limiter.try_acquire(b"status", start + 10_000 * MS), Ok(()));
+        assert!(limiter.try_acquire(b"status", start + 10_000 * MS).is_err());
+    }
+
+    #[test]
+    fn longest_prefix_uses_its_own_bucket() {
+        let limit = RateLimit::new(1, 1.0)
+            .prefix("whitelist", RateLimit::new(2, 1.0))
+            .prefix("whitelist add", RateLimit::new(1, 1.0));
+        let mut limiter = RateLimiter::new(Some(&limit));
+        let start = Instant::now();
+
+        assert_eq!(limiter.try_acquire(b"whitelist add bob", start), Ok(()));
+        assert!(limiter.try_acquire(b" whitelist add alice", start).is_err());
+        assert_eq!(limiter.try_acquire(b"whitelist list", start), Ok(()));
+        assert_eq!(limiter.try_acquire(b"whitelist list", start), Ok(()));
+        assert_eq!(limiter.try_acquire(b"status", start), Ok(()));
+        assert!(limiter.try_acquire(b"status", start).is_err());
+    }
+
+    #[test]
+    fn validate_rejects_limits_which_never_refill() {
+        assert!(RateLimiter::new(Some(&RateLimit::new(1, 1.0))).validate().is_ok());
+        assert!(RateLimiter::new(None).validate().is_ok());
+
+        for per_second in [0.0, -1.0, f64::NAN] {
+            let limiter = RateLimiter::new(Some(&RateLimit::new(1, per_second)));
+            assert!(matches!(limiter.validate(), Err(RconError::ClientError(_))), "{}", per_second);
+        }
+        let limit = RateLimit::new(1, 1.0).prefix("ban", RateLimit::new(1, 0.0));
+        assert!(RateLimiter::new(Some(&limit)).validate().is_err());
+    }
+
+    #[test]
+    fn no_limit_never_waits() {
+        let mut limiter = RateLimiter::new(None);
+
+        for _ in 0..100 {
+            assert_eq!(limiter.try_acquire(b"status", Instant::now()), Ok(()));
+        }
+    }
+
+    #[cfg(feature = "async")]
+    mod client {
+        use tokio::io::DuplexStream;
+
+        use super::*;
+        use crate::client::RconClient;
+        use crate::test_support::{config, echo_connector};
+
+        async fn client(limit: RateLimit) -> RconClient<DuplexStream> {
+            let config = config()
+                .io_timeout(50 * MS)
+                .idle_timeout(10 * MS)
+                .rate_limit(limit);
+            RconClient::connect_with(config, echo_connector(Default::default())).await.unwrap()
+        }
+
+        #[tokio::test]
+        async fn execute_waits_for_token_outside_io_timeout() {
+            let mut client = client(RateLimit::new(1, 10.0)).await;
+            let start = Instant::now();
+
+            assert_eq!(client.execute("a").await.unwrap(), "a");
+            assert_eq!(client.execute("b").await.unwrap(), "b");
+            assert!(start.elapsed() >= 90 * MS, "{:?}", start.elapsed());
+        }
+
+        #[tokio::test]
+        async fn cancelled_wait_does_not_use_token() {
+            let mut client = client(RateLimit::new(1, 10.0)).await;
+            client.execute("a").await.unwrap();
+
+            assert!(tokio::time::timeout(20 * MS, client.execute("b")).await.is_err());
+            tokio::time::sleep(100 * MS).await;
+
+            let start = Instant::now();
+            assert_eq!(client.execute("c").await.unwrap(), "c");
+            assert!(start.elapsed() < 50 * MS, "{:?}", start.elapsed());
+        }
+
+        #[tokio::test]
+        async fn execute_fails_when_rate_never_refills() {
+            let mut client = client(RateLimit::new(1, 0.0)).await;
+
+            let res = tokio::time::timeout(50 * MS, client.execute("a")).await.unwrap();
+            assert!(matches!(res, Err(RconError::ClientError(_))), "{:?}", res);
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! response.rs
+//!
+//! Collects the packets belonging to a single command into one response, 
+//! using the configured `ResponseFraming` to decide when the response is complete.
+
+use crate::client_config::ResponseFraming;
+use crate::common::PacketType;
+use crate::dialect::Dialect;
+use crate::errors::RconError;
+use crate::packet::Packet;
+
+#[derive(Debug)]
+pub(crate) struct ResponseAssembler {
+    framing: ResponseFraming,
+    dialect: Dialect,
+    cmd_id: i32,
+    mirror_id: Option<i32>,
+    lenient_ids: bool,
+    out: Vec<u8>,
+    data_seen: bool,
+}
+
+impl ResponseAssembler {
+    pub(crate) fn new(framing: ResponseFraming, dialect: Dialect, cmd_id: i32, mirror_id: Option<i32>) -> Self {
+        ResponseAssembler {
+            framing,
+            dialect,
+            cmd_id,
+            mirror_id,
+            lenient_ids: false,
+            out: Vec::new(),
+            data_seen: false,
+        }
+    }
+
+    /// Treats packets with any id as part of the response, for servers which do not echo command ids.
+    pub(crate) fn lenient_ids(mut self, v: bool) -> Self { self.lenient_ids = v; self }
+
+    /// Adds a received packet to the response.
+    /// 
+    /// ### Returns
+    /// - true once the response is complete, and no further packets should be read for it.
+    pub(crate) fn push(&mut self, pkt: Packet) -> bool {
+        if self.mirror_id == Some(pkt.id) {
+            log::debug!("Received mirrored packet with id {:?}, returning response", pkt.id);
+            return true;
+        }
+        if self.is_unmatched(&pkt) {
+            log::debug!("Received packet with id {:?} while waiting for response to command with id {:?}, ignoring", pkt.id, self.cmd_id);
+            return false;
+        }
+        if pkt.id != self.cmd_id {
+            log::debug!("Received packet with id {:?} while waiting for response to command with id {:?}, accepting", pkt.id, self.cmd_id);
+        }
+        if pkt.packet_type != PacketType::ServerDataResponseValue {
+            log::debug!(
+                "Received packet with unexpected type {:?} while waiting for command response, ignoring", 
+                pkt.packet_type
+            );
+            return false;
+        }
+        if self.framing == ResponseFraming::EmptyPacket && pkt.body.is_empty() {
+            log::debug!("Received empty packet for command with id {:?}, returning response", self.cmd_id);
+            return true;
+        }
+
+        self.data_seen = true;
+        self.out.extend_from_slice(&pkt.body);
+
+        if self.dialect.is_final_fragment(&pkt.body) {
+            log::debug!("Received final fragment for command with id {:?}, returning response", self.cmd_id);
+            return true;
+        }
+        false
+    }
+
+    /// Whether a packet belongs to neither this command nor its mirror packet, eg: an event pushed by the server.
+    pub(crate) fn is_unmatched(&self, pkt: &Packet) -> bool {
+        pkt.id != self.cmd_id && self.mirror_id != Some(pkt.id) && !self.lenient_ids
+    }
+
+    /// Called when `idle_timeout` passes without a packet, finishing the response.
+    pub(crate) fn idle(&self) {
+        if self.data_seen {
+            log::debug!("Idle timeout reached while waiting for more data, returning response");
+        } else {
+            log::debug!("Idle timeout reached without receiving any data, returning empty response");
+        }
+    }
+
+    pub(crate) fn finish(self) -> Vec<u8> {
+        self.out
+    }
+}
+
+/// Checks a packet received while authenticating.
+///
+/// ### Returns
+/// - true once the server has accepted the login, false for packets which are not the auth response.
+pub(crate) fn check_auth_response(pkt: &Packet, expected_id: i32, lenient_ids: bool) -> Result<bool, RconError> {
+    if pkt.packet_type != PacketType::ServerDataAuthResponse {
+        return Ok(false);
+    }
+    if pkt.id == -1 {
+        return Err(RconError::AuthFailed);
+    }
+    if pkt.id != expected_id && lenient_ids {
+        log::debug!("Auth response id {:?} does not match request id {:?}, accepting", pkt.id, expected_id);
+    } else if pkt.id != expected_id {
+        return Err(
+            RconError::Protocol(format!("Auth packet id response mismatch. Expected: {:?}, got: {:?}", expected_id, pkt.id))
+        )
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{Direction, Phase};
+
+    fn packet(id: i32, body: &str) -> Packet {
+        Packet { id, packet_type: PacketType::ServerDataResponseValue, body: body.as_bytes().to_vec() }
+    }
+
+    #[test]
+    fn push_ignores_unrelated_ids() {
+        let mut assembler = ResponseAssembler::new(ResponseFraming::IdleTimeout, Dialect::Source, 1, None);
+
+        assert!(!assembler.push(packet(2, "unrelated")));
+        assert!(!assembler.push(packet(1, "hello")));
+        assert_eq!(assembler.finish(), b"hello");
+    }
+
+    #[test]
+    fn push_accepts_mismatched_ids_when_lenient() {
+        let mut assembler = ResponseAssembler::new(ResponseFraming::IdleTimeout, Dialect::Source, 1, None)
+            .lenient_ids(true);
+
+        assert!(!assembler.push(packet(0, "hello ")));
+        assert!(!assembler.push(packet(7, "world")));
+        assert_eq!(assembler.finish(), b"hello world");
+    }
+
+    #[test]
+    fn push_ignores_unknown_types() {
+        let mut assembler = ResponseAssembler::new(ResponseFraming::EmptyPacket, Dialect::Source, 1, None);
+        let stray = Packet { id: 1, packet_type: PacketType::Unknown(5), body: Vec::new() };
+
+        assert!(!assembler.push(stray));
+        assert!(!assembler.push(packet(1, "hello")));
+        assert!(assembler.push(packet(1, "")));
+        assert_eq!(assembler.finish(), b"hello");
+    }
+
+    #[test]
+    fn push_accepts_type_2_responses() {
+        let mut assembler = ResponseAssembler::new(ResponseFraming::IdleTimeout, Dialect::Source, 1, None);
+        let packet_type = PacketType::decode(2, Direction::ServerToClient, Phase::Command);
+        let pkt = Packet { id: 1, packet_type, body: b"hello".to_vec() };
+
+        assert!(!assembler.push(pkt));
+        assert_eq!(assembler.finish(), b"hello");
+    }
+
+    #[test]
+    fn push_completes_on_mirror_id() {
+        let mut assembler = ResponseAssembler::new(ResponseFraming::Mirror, Dialect::Source, 1, Some(2));
+
+        assert!(!assembler.push(packet(1, "hello ")));
+        assert!(!assembler.push(packet(1, "world")));
+        assert!(assembler.push(packet(2, "")));
+        assert_eq!(assembler.finish(), b"hello world");
+    }
+
+    #[test]
+    fn push_completes_on_empty_packet() {
+        let mut assembler = ResponseAssembler::new(ResponseFraming::EmptyPacket, Dialect::Source, 1, None);
+
+        assert!(!assembler.push(packet(1, "hello")));
+        assert!(assembler.push(packet(1, "")));
+        assert_eq!(assembler.finish(), b"hello");
+    }
+
+    #[test]
+    fn push_completes_on_short_minecraft_fragment() {
+        let mut assembler = ResponseAssembler::new(ResponseFraming::IdleTimeout, Dialect::Minecraft, 1, None);
+        let full_fragment = "a".repeat(4096);
+
+        assert!(!assembler.push(packet(1, &full_fragment)));
+        assert!(assembler.push(packet(1, "bc")));
+        assert_eq!(assembler.finish(), (full_fragment + "bc").into_bytes());
+    }
+}
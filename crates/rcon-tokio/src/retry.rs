@@ -0,0 +1,267 @@
+//! retry.rs
+//!
+//! Controls how `execute` retries a failed command: how long to back off between reconnect attempts,
+//! and whether a command may be sent again once it could have reached the server.
+
+use std::time::Duration;
+
+use crate::errors::{RconError, SendError};
+
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(5);
+const DEFAULT_MULTIPLIER: u32 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub multiplier: u32,
+    pub jitter: bool,
+    pub idempotent_by_default: bool,
+    pub idempotent_prefixes: Vec<String>,
+    pub non_idempotent_prefixes: Vec<String>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            multiplier: DEFAULT_MULTIPLIER,
+            jitter: true,
+            idempotent_by_default: true,
+            idempotent_prefixes: Vec::new(),
+            non_idempotent_prefixes: Vec::new(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How long to wait before the first reconnect attempt.
+    pub fn initial_backoff(mut self, t: Duration) -> Self { self.initial_backoff = t; self }
+
+    /// The upper bound on the wait between reconnect attempts.
+    pub fn max_backoff(mut self, t: Duration) -> Self { self.max_backoff = t; self }
+
+    /// How much the wait grows after each failed attempt.
+    pub fn multiplier(mut self, v: u32) -> Self { self.multiplier = v; self }
+
+    /// Whether to randomise each wait to between half and all of its computed value,
+    /// so that many clients do not reconnect to a restarting server in lockstep.
+    pub fn jitter(mut self, v: bool) -> Self { self.jitter = v; self }
+
+    /// Whether commands which match neither prefix list are safe to send again,
+    /// after a failure which happened once the command had been written to the server.
+    ///
+    /// Defaults to true. When a command is not idempotent, such failures are returned to the caller instead.
+    pub fn idempotent_by_default(mut self, v: bool) -> Self { self.idempotent_by_default = v; self }
+
+    /// Marks commands starting with `prefix` as safe to send again, eg: `status`.
+    pub fn idempotent_prefix(mut self, prefix: &str) -> Self {
+        self.idempotent_prefixes.push(prefix.to_string());
+        self
+    }
+
+    /// Marks commands starting with `prefix` as unsafe to send again, eg: `ban` or `give`.
+    /// Takes precedence over `idempotent_prefix`.
+    pub fn non_idempotent_prefix(mut self, prefix: &str) -> Self {
+        self.non_idempotent_prefixes.push(prefix.to_string());
+        self
+    }
+
+    /// Whether `command` may be sent again after it could have reached the server.
+    pub fn is_idempotent(&self, command: &str) -> bool {
+        let command = command.trim_start();
+        if self.non_idempotent_prefixes.iter().any(|p| command.starts_with(p.as_str())) {
+            return false;
+        }
+        if self.idempotent_prefixes.iter().any(|p| command.starts_with(p.as_str())) {
+            return true;
+        }
+        self.idempotent_by_default
+    }
+
+    /// The wait before reconnect attempt number `retry`, starting from 0.
+    pub fn backoff(&self, retry: usize) -> Duration {
+        let factor = self.multiplier.saturating_pow(retry.try_into().unwrap_or(u32::MAX));
+        let backoff = self.initial_backoff.saturating_mul(factor).min(self.max_backoff);
+
+        if self.jitter {
+            backoff.mul_f64(0.5 + fastrand::f64() / 2.0)
+        } else {
+            backoff
+        }
+    }
+}
+
+/// The next step of `Attempts`.
+#[derive(Debug)]
+pub(crate) enum Attempt {
+    /// Send the command over the current connection.
+    Send,
+    /// Wait out the backoff, reconnect, then send the command again.
+    Reconnect(Duration),
+    /// Stop, returning the error to the caller.
+    GiveUp(RconError),
+}
+
+/// The retry loop of `execute`, without the IO, so the async and blocking clients decide alike when to
+/// send a command again and which error the caller sees.
+#[derive(Debug)]
+pub(crate) struct Attempts<'a> {
+    policy: &'a RetryPolicy,
+    command: &'a [u8],
+    auto_reconnect: bool,
+    max_attempts: usize,
+    attempt: usize,
+    last_err: Option<RconError>,
+}
+
+impl<'a> Attempts<'a> {
+    pub(crate) fn new(policy: &'a RetryPolicy, auto_reconnect: bool, max_attempts: usize, command: &'a [u8]) -> Self {
+        Attempts { policy, command, auto_reconnect, max_attempts, attempt: 0, last_err: None }
+    }
+
+    /// The number of the current attempt, starting from 1.
+    #[cfg(feature = "async")]
+    pub(crate) fn number(&self) -> usize {
+        self.attempt
+    }
+
+    /// Moves on to the next attempt.
+    /// Once out of attempts, gives up with the error of the last one, so callers can still match on it.
+    pub(crate) fn next(&mut self) -> Attempt {
+        self.attempt += 1;
+        if self.attempt > self.max_attempts || (self.attempt > 1 && !self.auto_reconnect) {
+            let made = self.attempt - 1;
+            return Attempt::GiveUp(match self.last_err.take() {
+                Some(e) => {
+                    log::warn!("Giving up on command after {} attempt(s): {}", made, e);
+                    e
+                },
+                None => RconError::ClientError("Command was not sent, max_reconnect_attempts is 0".to_string()),
+            });
+        }
+        if self.attempt == 1 {
+            log::debug!("Executing command with attempt {}/{}", self.attempt, self.max_attempts);
+            return Attempt::Send;
+        }
+
+        let backoff = self.policy.backoff(self.attempt - 2);
+        log::warn!("Attempting to reconnect client in {:?} and retry command execution", backoff);
+        Attempt::Reconnect(backoff)
+    }
+
+    pub(crate) fn reconnect_failed(&mut self, e: RconError) {
+        log::warn!("Failed to reconnect on attempt {}/{}. Error: {:?}", self.attempt, self.max_attempts, e);
+        self.last_err = Some(e);
+    }
+
+    /// Records a failed send, or returns the error if the command may have reached the server and
+    /// must not be sent again.
+    pub(crate) fn send_failed(&mut self, e: SendError) -> Result<(), RconError> {
+        if e.reached_server {
+            return self.response_failed(e.error);
+        }
+        log::warn!("Failed to send command on attempt {}/{}. Error: {:?}", self.attempt, self.max_attempts, e.error);
+        self.last_err = Some(e.error);
+        Ok(())
+    }
+
+    /// Records a failure once the command was sent, or returns the error if the command must not be sent again.
+    pub(crate) fn response_failed(&mut self, e: RconError) -> Result<(), RconError> {
+        if !self.policy.is_idempotent(&String::from_utf8_lossy(self.command)) {
+            log::warn!("Command may have reached the server and is not idempotent, not retrying. Error: {:?}", e);
+            return Err(e);
+        }
+        log::warn!("Failed to execute command on attempt {}/{}. Error: {:?}", self.attempt, self.max_attempts, e);
+        self.last_err = Some(e);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_exponentially_up_to_max() {
+        let policy = RetryPolicy::new()
+            .initial_backoff(Duration::from_millis(100))
+            .max_backoff(Duration::from_millis(500))
+            .jitter(false);
+
+        assert_eq!(policy.backoff(0), Duration::from_millis(100));
+        assert_eq!(policy.backoff(1), Duration::from_millis(200));
+        assert_eq!(policy.backoff(2), Duration::from_millis(400));
+        assert_eq!(policy.backoff(3), Duration::from_millis(500));
+        assert_eq!(policy.backoff(100), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn backoff_jitter_stays_within_half_of_computed_value() {
+        let policy = RetryPolicy::new().initial_backoff(Duration::from_millis(100));
+
+        for _ in 0..100 {
+            let backoff = policy.backoff(0);
+            assert!(backoff >= Duration::from_millis(50));
+            assert!(backoff <= Duration::from_millis(100));
+        }
+    }
+
+    #[test]
+    fn is_idempotent_prefers_non_idempotent_prefixes() {
+        let policy = RetryPolicy::new()
+            .idempotent_by_default(false)
+            .idempotent_prefix("status")
+            .idempotent_prefix("/")
+            .non_idempotent_prefix("/ban");
+
+        assert!(policy.is_idempotent("status"));
+        assert!(policy.is_idempotent("/players"));
+        assert!(!policy.is_idempotent(" /ban griefer"));
+        assert!(!policy.is_idempotent("give player diamond"));
+    }
+
+    #[test]
+    fn attempts_reconnect_with_backoff_until_out_of_attempts() {
+        let policy = RetryPolicy::new().initial_backoff(Duration::from_millis(100)).jitter(false);
+        let mut attempts = Attempts::new(&policy, true, 3, b"status");
+
+        assert!(matches!(attempts.next(), Attempt::Send));
+        attempts.send_failed(SendError::unsent(RconError::Timeout)).unwrap();
+        assert!(matches!(attempts.next(), Attempt::Reconnect(b) if b == Duration::from_millis(100)));
+        attempts.response_failed(RconError::Timeout).unwrap();
+        assert!(matches!(attempts.next(), Attempt::Reconnect(b) if b == Duration::from_millis(200)));
+        attempts.reconnect_failed(RconError::AuthFailed);
+
+        assert!(matches!(attempts.next(), Attempt::GiveUp(RconError::AuthFailed)));
+    }
+
+    #[test]
+    fn attempts_give_up_without_auto_reconnect() {
+        let policy = RetryPolicy::new();
+        let mut attempts = Attempts::new(&policy, false, 3, b"status");
+
+        assert!(matches!(attempts.next(), Attempt::Send));
+        attempts.response_failed(RconError::Timeout).unwrap();
+        assert!(matches!(attempts.next(), Attempt::GiveUp(RconError::Timeout)));
+    }
+
+    #[test]
+    fn attempts_only_resend_non_idempotent_commands_which_never_reached_the_server() {
+        let policy = RetryPolicy::new().non_idempotent_prefix("ban");
+        let mut attempts = Attempts::new(&policy, true, 3, b"ban griefer");
+
+        assert!(matches!(attempts.next(), Attempt::Send));
+        attempts.send_failed(SendError::unsent(RconError::Timeout)).unwrap();
+        assert!(matches!(attempts.next(), Attempt::Reconnect(_)));
+        let sent = SendError { error: RconError::Timeout, reached_server: true };
+        assert!(matches!(attempts.send_failed(sent), Err(RconError::Timeout)));
+        assert!(matches!(attempts.response_failed(RconError::Timeout), Err(RconError::Timeout)));
+    }
+}
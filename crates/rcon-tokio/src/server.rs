@@ -0,0 +1,342 @@
+//! server.rs
+//!
+//! A small, embeddable RCON server speaking the same wire format as the client.
+//! https://developer.valvesoftware.com/wiki/Source_RCON_Protocol
+//!
+//! The server authenticates connections against a single password, and hands the body of every
+//! SERVERDATA_EXECCOMMAND packet to a user supplied `CommandHandler`. Replies larger than a single
+//! packet are split across multiple SERVERDATA_RESPONSE_VALUE packets sharing the request id.
+//! Connections which send nothing for `read_timeout` are closed, so idle or stalled clients do not
+//! hold a task forever.
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::time::timeout;
+
+use crate::packet::{build_packet, read_packet, MAXIMUM_RESPONSE_BODY_SIZE};
+use crate::{common::{Direction, PacketType, Phase}, dialect::Dialect, errors::RconError};
+
+/// Handles commands sent by authenticated clients.
+pub trait CommandHandler: Send + Sync + 'static {
+    /// Execute `command`, returning the response body to send back to the client.
+    fn handle(&self, command: String) -> impl Future<Output = String> + Send;
+}
+
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(120);
+/// How long to stop accepting after an error such as running out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+pub struct RconServer<H> {
+    password: String,
+    handler: Arc<H>,
+    read_timeout: Duration,
+}
+
+impl<H> Clone for RconServer<H> {
+    fn clone(&self) -> Self {
+        RconServer {
+            password: self.password.clone(),
+            handler: Arc::clone(&self.handler),
+            read_timeout: self.read_timeout,
+        }
+    }
+}
+
+impl<H: CommandHandler> RconServer<H> {
+    pub fn new(password: String, handler: H) -> Self {
+        RconServer {
+            password,
+            handler: Arc::new(handler),
+            read_timeout: DEFAULT_READ_TIMEOUT,
+        }
+    }
+
+    /// How long to wait for each packet from a client before closing its connection.
+    pub fn read_timeout(mut self, t: Duration) -> Self {
+        self.read_timeout = t;
+        self
+    }
+
+    /// Accepts connections from `listener` forever, serving each one on its own task.
+    ///
+    /// Errors accepting a connection are logged and do not stop the server. After errors other than
+    /// the peer giving up, eg: running out of file descriptors, accepting pauses briefly.
+    pub async fn listen(self, listener: TcpListener) -> Result<(), RconError> {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) if is_peer_error(&e) => {
+                    log::debug!("Failed to accept connection: {}", e);
+                    continue;
+                },
+                Err(e) => {
+                    log::warn!("Failed to accept connection, pausing for {:?}: {}", ACCEPT_BACKOFF, e);
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                },
+            };
+            log::debug!("Accepted connection from {}", peer);
+
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.serve(stream).await {
+                    log::warn!("Connection from {} closed with error: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    /// Serves a single client connection until it is closed by the peer.
+    ///
+    /// Connections which send a command before authenticating are dropped, as Source servers do.
+    /// Returns `RconError::Timeout` if the client sends no complete packet within `read_timeout`.
+    pub async fn serve<S: AsyncRead + AsyncWrite + Unpin>(&self, mut stream: S) -> Result<(), RconError> {
+        let mut authenticated = false;
+
+        loop {
+            let phase = if authenticated { Phase::Command } else { Phase::Auth };
+            let read = read_packet(&mut stream, Dialect::Source, Direction::ClientToServer, phase);
+            let pkt = match timeout(self.read_timeout, read).await.map_err(|_| RconError::Timeout)? {
+                Ok(pkt) => pkt,
+                Err(RconError::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    log::debug!("Client closed connection");
+                    return Ok(());
+                },
+                Err(e) => return Err(e),
+            };
+
+            match pkt.packet_type {
+                PacketType::ServerDataAuth => {
+                    authenticated = pkt.body == self.password.as_bytes();
+                    let auth_id = if authenticated { pkt.id } else { -1 };
+
+                    // Source servers send an empty response value ahead of the auth response
+                    send_packet(&mut stream, pkt.id, PacketType::ServerDataResponseValue, "").await?;
+                    send_packet(&mut stream, auth_id, PacketType::ServerDataAuthResponse, "").await?;
+                },
+                PacketType::ServerDataExecCommand if !authenticated => {
+                    log::debug!("Received command before authentication, closing connection");
+                    return Ok(());
+                },
+                PacketType::ServerDataExecCommand => {
+                    // Commands which are not valid utf8 are still answered, rather than dropping the connection
+                    let command = String::from_utf8_lossy(&pkt.body).into_owned();
+                    let response = self.handler.handle(command).await;
+                    for chunk in split_response(&response) {
+                        send_packet(&mut stream, pkt.id, PacketType::ServerDataResponseValue, chunk).await?;
+                    }
+                },
+                PacketType::ServerDataResponseValue => {
+                    // Mirror empty response values back, clients use these to find the end of a response
+                    send_packet(&mut stream, pkt.id, PacketType::ServerDataResponseValue, "").await?;
+                },
+                PacketType::ServerDataAuthResponse | PacketType::Unknown(_) => {
+                    log::debug!("Received unexpected {:?} packet with id {:?} from client, ignoring", pkt.packet_type, pkt.id);
+                },
+            }
+        }
+    }
+}
+
+/// Whether an accept error only concerns the connection being accepted, not the listener.
+fn is_peer_error(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    matches!(e.kind(), ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset | ErrorKind::Interrupted)
+}
+
+async fn send_packet<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    id: i32,
+    packet_type: PacketType,
+    body: &str,
+) -> Result<(), RconError> {
+    let buf = build_packet(id, packet_type, body.as_bytes(), Dialect::Source)?;
+    stream.write_all(&buf).await?;
+    Ok(())
+}
+
+/// Splits a response into bodies which each fit into a single packet, without splitting utf8 characters.
+/// An empty response still produces a single empty body.
+fn split_response(response: &str) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut rest = response;
+
+    while rest.len() > MAXIMUM_RESPONSE_BODY_SIZE {
+        let mut end = MAXIMUM_RESPONSE_BODY_SIZE;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (chunk, tail) = rest.split_at(end);
+        chunks.push(chunk);
+        rest = tail;
+    }
+    chunks.push(rest);
+
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::test_support::{self, Echo, MAX_BUFFER_SIZE};
+    use crate::{RconClient, RconClientConfig};
+    use tokio::io::duplex;
+
+    struct Repeat(usize);
+
+    impl CommandHandler for Repeat {
+        async fn handle(&self, command: String) -> String {
+            command.repeat(self.0)
+        }
+    }
+
+    #[test]
+    fn split_response_keeps_small_responses_whole() {
+        assert_eq!(split_response(""), vec![""]);
+        assert_eq!(split_response("hello"), vec!["hello"]);
+    }
+
+    #[test]
+    fn split_response_respects_char_boundaries() {
+        let response = "é".repeat(MAXIMUM_RESPONSE_BODY_SIZE);
+
+        let chunks = split_response(&response);
+
+        assert!(chunks.iter().all(|c| c.len() <= MAXIMUM_RESPONSE_BODY_SIZE));
+        assert_eq!(chunks.concat(), response);
+    }
+
+    #[tokio::test]
+    async fn serve_authenticates_and_dispatches_commands() {
+        let (client_stream, server_stream) = duplex(MAX_BUFFER_SIZE);
+        let server = RconServer::new("pw".to_string(), Echo);
+        let server_task = tokio::spawn(async move { server.serve(server_stream).await });
+
+        let mut client = RconClient::new(client_stream).with_client_config(test_support::config().io_timeout(Duration::from_secs(1)));
+        client.authenticate().await.unwrap();
+
+        let id = client.write_packet(PacketType::ServerDataExecCommand, b"hello").await.unwrap();
+        let pkt = client.read_packet(Phase::Command).await.unwrap();
+
+        assert_eq!(pkt.id, id);
+        assert_eq!(pkt.packet_type, PacketType::ServerDataResponseValue);
+        assert_eq!(pkt.body, b"hello");
+
+        drop(client);
+        server_task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn serve_rejects_wrong_password() {
+        let (client_stream, server_stream) = duplex(MAX_BUFFER_SIZE);
+        let server = RconServer::new("pw".to_string(), Echo);
+        tokio::spawn(async move { server.serve(server_stream).await });
+
+        let mut client = RconClient::new(client_stream)
+            .with_client_config(RconClientConfig {
+                password: "wrong".to_string(),
+                io_timeout: Duration::from_secs(1),
+                ..Default::default()
+            });
+
+        let res = client.authenticate().await;
+        assert!(matches!(res, Err(RconError::AuthFailed)));
+    }
+
+    #[tokio::test]
+    async fn serve_closes_unauthenticated_connections() {
+        let (client_stream, server_stream) = duplex(MAX_BUFFER_SIZE);
+        let server = RconServer::new("pw".to_string(), Echo);
+        let server_task = tokio::spawn(async move { server.serve(server_stream).await });
+
+        let mut client = RconClient::new(client_stream);
+        client.write_packet(PacketType::ServerDataExecCommand, b"hello").await.unwrap();
+
+        server_task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn serve_answers_commands_which_are_not_utf8() {
+        let (client_stream, server_stream) = duplex(MAX_BUFFER_SIZE);
+        let server = RconServer::new("pw".to_string(), Echo);
+        tokio::spawn(async move { server.serve(server_stream).await });
+
+        let mut client = RconClient::new(client_stream).with_client_config(test_support::config().io_timeout(Duration::from_secs(1)));
+        client.authenticate().await.unwrap();
+
+        client.write_packet(PacketType::ServerDataExecCommand, b"say \xff").await.unwrap();
+        let pkt = client.read_packet(Phase::Command).await.unwrap();
+        assert_eq!(pkt.body, "say \u{fffd}".as_bytes());
+
+        client.write_packet(PacketType::ServerDataExecCommand, b"status").await.unwrap();
+        assert_eq!(client.read_packet(Phase::Command).await.unwrap().body, b"status");
+    }
+
+    #[tokio::test]
+    async fn serve_closes_idle_connections() {
+        let (client_stream, server_stream) = duplex(MAX_BUFFER_SIZE);
+        let server = RconServer::new("pw".to_string(), Echo).read_timeout(Duration::from_millis(20));
+        let server_task = tokio::spawn(async move { server.serve(server_stream).await });
+
+        // Half a packet, then nothing
+        let mut client_stream = client_stream;
+        client_stream.write_all(&[14, 0, 0, 0, 1]).await.unwrap();
+
+        let res = tokio::time::timeout(Duration::from_secs(1), server_task).await.unwrap().unwrap();
+        assert!(matches!(res, Err(RconError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn serve_splits_large_responses() {
+        let (client_stream, server_stream) = duplex(MAX_BUFFER_SIZE);
+        let server = RconServer::new("pw".to_string(), Repeat(1000));
+        tokio::spawn(async move { server.serve(server_stream).await });
+
+        let mut client = RconClient::new(client_stream).with_client_config(test_support::config().io_timeout(Duration::from_secs(1)));
+        client.authenticate().await.unwrap();
+
+        let id = client.write_packet(PacketType::ServerDataExecCommand, b"abcdefghij").await.unwrap();
+        let mut out = String::new();
+        while out.len() < 10_000 {
+            let pkt = client.read_packet(Phase::Command).await.unwrap();
+            assert_eq!(pkt.id, id);
+            assert!(pkt.body.len() <= MAXIMUM_RESPONSE_BODY_SIZE);
+            out.push_str(std::str::from_utf8(&pkt.body).unwrap());
+        }
+
+        assert_eq!(out, "abcdefghij".repeat(1000));
+    }
+
+    #[tokio::test]
+    async fn listen_serves_tcp_clients() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(RconServer::new("pw".to_string(), Echo).listen(listener));
+
+        let config = RconClientConfig::new(addr.ip().to_string(), addr.port(), "pw".to_string());
+        let mut client = RconClient::connect(config).await.unwrap();
+
+        assert_eq!(client.execute("status").await.unwrap(), "status");
+    }
+
+    #[tokio::test]
+    async fn listen_mirrors_response_values() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(RconServer::new("pw".to_string(), Repeat(1000)).listen(listener));
+
+        let config = RconClientConfig::new(addr.ip().to_string(), addr.port(), "pw".to_string())
+            .response_framing(crate::ResponseFraming::Mirror);
+        let mut client = RconClient::connect(config).await.unwrap();
+
+        assert_eq!(client.execute("abcdefghij").await.unwrap(), "abcdefghij".repeat(1000));
+        assert_eq!(client.execute("x").await.unwrap(), "x".repeat(1000));
+    }
+}
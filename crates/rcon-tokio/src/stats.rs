@@ -0,0 +1,183 @@
+//! stats.rs
+//!
+//! Instrumentation of `RconClient`, enabled with the `metrics` feature.
+//!
+//! Events are recorded through the `metrics` facade, labelled with the server address, so any installed
+//! recorder such as a Prometheus exporter picks them up. Each client also keeps an `RconStats` snapshot
+//! for users who do not run a metrics backend. Without the feature, recording compiles to nothing.
+
+use std::time::Duration;
+
+use crate::common::Phase;
+
+/// Something worth counting which happened on a client's connection.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+pub(crate) enum Event {
+    /// A packet of this many bytes was written.
+    PacketSent(usize),
+    /// A packet of this many bytes was read.
+    PacketReceived(usize),
+    Timeout(Phase),
+    AuthFailed,
+    Reconnect,
+    /// A command finished after this long, including any retries.
+    Command { latency: Duration, ok: bool },
+}
+
+#[cfg(not(feature = "metrics"))]
+impl<S> crate::client::RconClient<S> {
+    #[inline]
+    pub(crate) fn record(&mut self, _event: Event) {}
+}
+
+#[cfg(feature = "metrics")]
+pub use recording::RconStats;
+
+#[cfg(feature = "metrics")]
+mod recording {
+    use std::time::Duration;
+
+    use metrics::{counter, histogram};
+
+    use super::Event;
+    use crate::client::RconClient;
+    use crate::common::Phase;
+
+    /// Totals for a client's connection since it was created, across reconnects.
+    #[derive(Debug, Default, Clone, PartialEq, Eq)]
+    pub struct RconStats {
+        pub commands: u64,
+        pub command_failures: u64,
+        pub packets_sent: u64,
+        pub packets_received: u64,
+        pub bytes_sent: u64,
+        pub bytes_received: u64,
+        pub auth_failures: u64,
+        pub auth_timeouts: u64,
+        pub command_timeouts: u64,
+        pub reconnects: u64,
+        pub total_command_latency: Duration,
+        pub max_command_latency: Duration,
+    }
+
+    impl RconStats {
+        /// The mean time taken by commands, or zero before any command has run.
+        pub fn mean_command_latency(&self) -> Duration {
+            match u32::try_from(self.commands) {
+                Ok(0) | Err(_) => Duration::ZERO,
+                Ok(n) => self.total_command_latency / n,
+            }
+        }
+    }
+
+    impl<S> RconClient<S> {
+        /// A snapshot of the client's counters.
+        pub fn stats(&self) -> RconStats {
+            self.stats.clone()
+        }
+
+        pub(crate) fn record(&mut self, event: Event) {
+            let address = self.client_config.server();
+            let stats = &mut self.stats;
+
+            match event {
+                Event::PacketSent(bytes) => {
+                    stats.packets_sent += 1;
+                    stats.bytes_sent += bytes as u64;
+                    counter!("rcon_packets_sent_total", "address" => address.clone()).increment(1);
+                    counter!("rcon_bytes_sent_total", "address" => address).increment(bytes as u64);
+                },
+                Event::PacketReceived(bytes) => {
+                    stats.packets_received += 1;
+                    stats.bytes_received += bytes as u64;
+                    counter!("rcon_packets_received_total", "address" => address.clone()).increment(1);
+                    counter!("rcon_bytes_received_total", "address" => address).increment(bytes as u64);
+                },
+                Event::Timeout(phase) => {
+                    let phase = match phase {
+                        Phase::Auth => { stats.auth_timeouts += 1; "auth" },
+                        Phase::Command => { stats.command_timeouts += 1; "command" },
+                    };
+                    counter!("rcon_timeouts_total", "address" => address, "phase" => phase).increment(1);
+                },
+                Event::AuthFailed => {
+                    stats.auth_failures += 1;
+                    counter!("rcon_auth_failures_total", "address" => address).increment(1);
+                },
+                Event::Reconnect => {
+                    stats.reconnects += 1;
+                    counter!("rcon_reconnects_total", "address" => address).increment(1);
+                },
+                Event::Command { latency, ok } => {
+                    stats.commands += 1;
+                    stats.total_command_latency += latency;
+                    stats.max_command_latency = stats.max_command_latency.max(latency);
+                    let outcome = if ok { "ok" } else { stats.command_failures += 1; "error" };
+                    histogram!("rcon_command_duration_seconds", "address" => address.clone()).record(latency.as_secs_f64());
+                    counter!("rcon_commands_total", "address" => address, "outcome" => outcome).increment(1);
+                },
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::io::duplex;
+
+    use super::*;
+    use crate::server::RconServer;
+    use crate::test_support::{config, echo_connector, Echo, MAX_BUFFER_SIZE};
+    use crate::{client::RconClient, errors::RconError};
+
+    #[tokio::test]
+    async fn stats_count_packets_bytes_and_commands() {
+        let mut client = RconClient::connect_with(config(), echo_connector(Arc::default())).await.unwrap();
+        client.execute("status").await.unwrap();
+
+        let stats = client.stats();
+        assert_eq!(stats.commands, 1);
+        assert_eq!(stats.command_failures, 0);
+        // Auth and command packets, with 14 bytes of framing each
+        assert_eq!(stats.packets_sent, 2);
+        assert_eq!(stats.bytes_sent, (14 + 2) + (14 + 6));
+        // The server answers auth with an empty response value and an auth response
+        assert_eq!(stats.packets_received, 3);
+        assert_eq!(stats.bytes_received, 14 * 3 + 6);
+        assert!(stats.max_command_latency > Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn stats_count_reconnects_and_timeouts_by_phase() {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let mut client = RconClient::connect_with(
+            config().auto_reconnect(true).max_reconnect_attempts(2).response_framing(crate::ResponseFraming::Mirror),
+            echo_connector(Arc::clone(&connects)),
+        ).await.unwrap();
+
+        // A peer which accepts writes but never replies
+        let (silent_stream, _peer) = duplex(MAX_BUFFER_SIZE);
+        client.stream = silent_stream;
+        assert_eq!(client.execute("status").await.unwrap(), "status");
+
+        let stats = client.stats();
+        assert_eq!((stats.command_timeouts, stats.auth_timeouts), (1, 0));
+        assert_eq!(stats.reconnects, 1);
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn stats_count_auth_failures() {
+        let (client_stream, server_stream) = duplex(MAX_BUFFER_SIZE);
+        tokio::spawn(async move { RconServer::new("other".to_string(), Echo).serve(server_stream).await });
+        let mut client = RconClient::new(client_stream).with_client_config(config());
+
+        assert!(matches!(client.authenticate().await, Err(RconError::AuthFailed)));
+        assert_eq!(client.stats().auth_failures, 1);
+        assert_eq!(client.stats().packets_sent, 1);
+    }
+}
@@ -0,0 +1,65 @@
+//! test_support.rs
+//!
+//! Stand-in servers, connectors and configs shared by the unit tests.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{duplex, DuplexStream};
+
+use crate::client_config::RconClientConfig;
+use crate::connect::Connector;
+use crate::server::{CommandHandler, RconServer};
+
+pub(crate) const MAX_BUFFER_SIZE: usize = 16384;
+
+/// How long `Echo` takes to answer `slow`.
+pub(crate) const SLOW_RESPONSE: Duration = Duration::from_millis(200);
+
+/// Answers every command with the command itself, except `slow` which is answered after `SLOW_RESPONSE`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Echo;
+
+impl CommandHandler for Echo {
+    async fn handle(&self, command: String) -> String {
+        if command == "slow" {
+            tokio::time::sleep(SLOW_RESPONSE).await;
+        }
+        command
+    }
+}
+
+/// Counts the commands passed on to `H`.
+#[derive(Debug, Clone)]
+pub(crate) struct Counted<H>(pub Arc<AtomicUsize>, pub H);
+
+impl<H: CommandHandler> CommandHandler for Counted<H> {
+    async fn handle(&self, command: String) -> String {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        self.1.handle(command).await
+    }
+}
+
+/// Serves `server` over a new in-memory stream on each connect, counting them in `connects`.
+pub(crate) fn serve_connector<H: CommandHandler>(server: RconServer<H>, connects: Arc<AtomicUsize>) -> impl Connector<DuplexStream> {
+    move |_: &RconClientConfig| {
+        connects.fetch_add(1, Ordering::SeqCst);
+        let (client_stream, server_stream) = duplex(MAX_BUFFER_SIZE);
+        let server = server.clone();
+        tokio::spawn(async move { server.serve(server_stream).await });
+        async move { Ok(client_stream) }
+    }
+}
+
+/// Serves `Echo` with password `pw`, counting connects in `connects`.
+pub(crate) fn echo_connector(connects: Arc<AtomicUsize>) -> impl Connector<DuplexStream> {
+    serve_connector(RconServer::new("pw".to_string(), Echo), connects)
+}
+
+/// A config for the stand-in servers, with password `pw` and short timeouts.
+pub(crate) fn config() -> RconClientConfig {
+    RconClientConfig::new("unused".to_string(), 0, "pw".to_string())
+        .io_timeout(Duration::from_millis(100))
+        .idle_timeout(Duration::from_millis(20))
+}
@@ -0,0 +1,339 @@
+//! tls.rs
+//!
+//! TLS transport, for servers which are only reachable through a TLS terminating proxy such as stunnel or HAProxy.
+//! Enabled with the `tls` feature, using rustls with the ring crypto provider.
+//!
+//! Certificates and keys are given as PEM, and parsed when connecting.
+//! Without a CA bundle or pins, the server is verified against the Mozilla root certificates.
+
+use std::fmt;
+use std::sync::Arc;
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use sha2::{Digest, Sha256};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::client_config::RconClientConfig;
+use crate::connect::{ConnectFuture, Connector, TcpConnector};
+use crate::{client::RconClient, errors::RconError};
+
+pub type TlsStream = tokio_rustls::client::TlsStream<TcpStream>;
+
+#[derive(Default, Clone, PartialEq, Eq)]
+pub struct TlsConfig {
+    pub ca_pem: Vec<u8>,
+    pub server_name: Option<String>,
+    pub client_cert_pem: Option<Vec<u8>>,
+    pub client_key_pem: Option<Vec<u8>>,
+    pub pinned_sha256: Vec<[u8; 32]>,
+}
+
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsConfig")
+            .field("ca_pem", &String::from_utf8_lossy(&self.ca_pem))
+            .field("server_name", &self.server_name)
+            .field("client_cert_pem", &self.client_cert_pem.as_deref().map(String::from_utf8_lossy))
+            .field("client_key_pem", &self.client_key_pem.as_ref().map(|_| "<redacted>"))
+            .field("pinned_sha256", &self.pinned_sha256)
+            .finish()
+    }
+}
+
+impl TlsConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trusts the CA certificates in `pem` instead of the Mozilla root certificates. May be called more than once.
+    pub fn ca_pem(mut self, pem: &[u8]) -> Self {
+        self.ca_pem.extend_from_slice(pem);
+        self.ca_pem.push(b'\n');
+        self
+    }
+
+    /// The name sent as SNI and checked against the server certificate. Defaults to the config's `address`.
+    pub fn server_name(mut self, name: &str) -> Self { self.server_name = Some(name.to_string()); self }
+
+    /// Presents a client certificate chain and its private key, for proxies which require mutual TLS.
+    pub fn client_cert_pem(mut self, cert_chain_pem: &[u8], key_pem: &[u8]) -> Self {
+        self.client_cert_pem = Some(cert_chain_pem.to_vec());
+        self.client_key_pem = Some(key_pem.to_vec());
+        self
+    }
+
+    /// Only accepts a server certificate whose DER encoding has this SHA-256 fingerprint. May be called more than once.
+    ///
+    /// Once any pin is set, the certificate is not otherwise validated, so self-signed certificates work.
+    pub fn pin_sha256(mut self, fingerprint: [u8; 32]) -> Self { self.pinned_sha256.push(fingerprint); self }
+
+    fn client_config(&self) -> Result<ClientConfig, RconError> {
+        let provider = Arc::new(crypto::ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?;
+
+        let builder = if !self.pinned_sha256.is_empty() {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier { pins: self.pinned_sha256.clone(), provider }))
+        } else {
+            let mut roots = RootCertStore::empty();
+            if self.ca_pem.is_empty() {
+                roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            }
+            for cert in CertificateDer::pem_slice_iter(&self.ca_pem) {
+                roots.add(cert.map_err(tls_error)?).map_err(tls_error)?;
+            }
+            builder.with_root_certificates(roots)
+        };
+
+        match (&self.client_cert_pem, &self.client_key_pem) {
+            (Some(cert_pem), Some(key_pem)) => {
+                let chain = CertificateDer::pem_slice_iter(cert_pem)
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(tls_error)?;
+                let key = PrivateKeyDer::from_pem_slice(key_pem).map_err(tls_error)?;
+                builder.with_client_auth_cert(chain, key).map_err(tls_error)
+            },
+            _ => Ok(builder.with_no_client_auth()),
+        }
+    }
+}
+
+fn tls_error(e: impl fmt::Display) -> RconError {
+    RconError::ClientError(format!("Invalid TLS config: {}", e))
+}
+
+/// Accepts exactly the pinned certificates, while still checking the handshake is signed by their keys.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    pins: Vec<[u8; 32]>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint: [u8; 32] = Sha256::digest(end_entity).into();
+        if self.pins.contains(&fingerprint) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            log::debug!("Server certificate does not match any pinned fingerprint");
+            Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Connects over TCP with `TcpConnector`, then performs a TLS handshake configured by the config's `tls`.
+/// Gives up on the handshake if the server does not finish it within `io_timeout`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TlsConnector;
+
+impl Connector<TlsStream> for TlsConnector {
+    fn connect<'a>(&'a self, config: &'a RconClientConfig) -> ConnectFuture<'a, TlsStream> {
+        Box::pin(async move {
+            let tls = config.tls.clone().unwrap_or_default();
+            let connector = tokio_rustls::TlsConnector::from(Arc::new(tls.client_config()?));
+
+            let name = tls.server_name.as_deref().unwrap_or(&config.address);
+            let server_name = ServerName::try_from(name.to_string())
+                .map_err(|e| RconError::ClientError(format!("Invalid TLS server name {:?}: {}", name, e)))?;
+
+            let stream = TcpConnector.connect(config).await?;
+            log::debug!("Starting TLS handshake with {:?}", server_name);
+            let handshake = connector.connect(server_name, stream);
+            Ok(timeout(config.io_timeout, handshake).await.map_err(|_| RconError::Timeout)??)
+        })
+    }
+}
+
+impl RconClient<TlsStream> {
+    /// Connects over TLS with `TlsConnector`, and authenticates.
+    pub async fn connect_tls(client_config: RconClientConfig) -> Result<Self, RconError> {
+        RconClient::connect_with(client_config, TlsConnector).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+    use rustls::server::WebPkiClientVerifier;
+    use rustls::ServerConfig;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::server::RconServer;
+    use crate::test_support::{self, Echo};
+
+    fn issue_ca() -> CertifiedIssuer<'static, KeyPair> {
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap()
+    }
+
+    fn issue_cert(ca: &CertifiedIssuer<'static, KeyPair>, name: &str) -> (Certificate, KeyPair) {
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec![name.to_string()]).unwrap().signed_by(&key, ca).unwrap();
+        (cert, key)
+    }
+
+    /// A TLS terminating stand-in serving `RconServer` with password `pw`, optionally requiring client certificates from `client_ca`.
+    async fn start_server(
+        cert: &Certificate,
+        key: &KeyPair,
+        client_ca: Option<&CertifiedIssuer<'static, KeyPair>>,
+    ) -> RconClientConfig {
+        let provider = Arc::new(crypto::ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()
+            .unwrap();
+        let builder = match client_ca {
+            Some(ca) => {
+                let mut roots = RootCertStore::empty();
+                roots.add(ca.der().clone()).unwrap();
+                let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build().unwrap();
+                builder.with_client_cert_verifier(verifier)
+            },
+            None => builder.with_no_client_auth(),
+        };
+        let key = PrivateKeyDer::from_pem_slice(key.serialize_pem().as_bytes()).unwrap();
+        let server_config = builder.with_single_cert(vec![cert.der().clone()], key).unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = RconServer::new("pw".to_string(), Echo);
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (acceptor, server) = (acceptor.clone(), server.clone());
+                tokio::spawn(async move {
+                    if let Ok(stream) = acceptor.accept(stream).await {
+                        let _ = server.serve(stream).await;
+                    }
+                });
+            }
+        });
+
+        RconClientConfig { address: addr.ip().to_string(), port: addr.port(), ..test_support::config() }
+            .io_timeout(Duration::from_secs(1))
+            .max_reconnect_attempts(1)
+    }
+
+    #[tokio::test]
+    async fn connect_tls_verifies_server_with_custom_ca_and_sni() {
+        let ca = issue_ca();
+        let (cert, key) = issue_cert(&ca, "rcon.example.com");
+        let config = start_server(&cert, &key, None).await;
+        let tls = TlsConfig::new().ca_pem(ca.pem().as_bytes()).server_name("rcon.example.com");
+
+        let mut client = RconClient::connect_tls(config.clone().tls(tls)).await.unwrap();
+        assert_eq!(client.execute("status").await.unwrap(), "status");
+
+        // Without the SNI override, the certificate does not match the address
+        let tls = TlsConfig::new().ca_pem(ca.pem().as_bytes());
+        assert!(RconClient::connect_tls(config.tls(tls)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn connect_tls_rejects_untrusted_server() {
+        let (cert, key) = issue_cert(&issue_ca(), "localhost");
+        let config = start_server(&cert, &key, None).await;
+
+        let res = RconClient::connect_tls(config.tls(TlsConfig::new().server_name("localhost"))).await;
+        assert!(matches!(res, Err(RconError::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn connect_tls_accepts_only_pinned_certificate() {
+        let (cert, key) = issue_cert(&issue_ca(), "localhost");
+        let config = start_server(&cert, &key, None).await;
+        let fingerprint: [u8; 32] = Sha256::digest(cert.der()).into();
+
+        let pinned = TlsConfig::new().pin_sha256(fingerprint);
+        assert!(RconClient::connect_tls(config.clone().tls(pinned)).await.is_ok());
+
+        let wrong_pin = TlsConfig::new().pin_sha256([0u8; 32]);
+        assert!(RconClient::connect_tls(config.tls(wrong_pin)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn connect_tls_presents_client_certificate() {
+        let ca = issue_ca();
+        let (cert, key) = issue_cert(&ca, "localhost");
+        let (client_cert, client_key) = issue_cert(&ca, "client");
+        let config = start_server(&cert, &key, Some(&ca)).await;
+        let tls = TlsConfig::new().ca_pem(ca.pem().as_bytes()).server_name("localhost");
+
+        let mut client = RconClient::connect_tls(
+            config.clone().tls(tls.clone().client_cert_pem(client_cert.pem().as_bytes(), client_key.serialize_pem().as_bytes()))
+        ).await.unwrap();
+        assert_eq!(client.execute("status").await.unwrap(), "status");
+
+        // TLS 1.3 servers reject a missing client certificate after the client finishes its handshake
+        let res = match RconClient::connect_tls(config.tls(tls)).await {
+            Ok(mut client) => client.execute("status").await.map(|_| ()),
+            Err(e) => Err(e),
+        };
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn connect_tls_times_out_when_server_never_answers_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let config = RconClientConfig::new(addr.ip().to_string(), addr.port(), "pw".to_string())
+            .io_timeout(Duration::from_millis(50));
+        let res = TlsConnector.connect(&config).await;
+        assert!(matches!(res, Err(RconError::Timeout)));
+    }
+
+    #[test]
+    fn debug_redacts_client_key() {
+        let tls = TlsConfig::new().client_cert_pem(b"cert", b"secret key");
+
+        assert!(!format!("{:?}", tls).contains("secret key"));
+    }
+}
@@ -0,0 +1,265 @@
+//! trace.rs
+//!
+//! Spans and events for `RconClient`, enabled with the `tracing` feature.
+//!
+//! Connecting, authenticating and each command run in their own span, labelled with the server, so RCON
+//! calls show up under whatever request made them. Spans record ids, attempts, packet counts and durations
+//! as fields, never the password or command bodies. Without the feature, spans compile to nothing, and
+//! events are logged through `log` instead, with the same fields.
+
+#[cfg(not(feature = "tracing"))]
+use std::future::Future;
+
+#[cfg(not(feature = "tracing"))]
+use crate::{client_config::RconClientConfig, errors::RconError, packet::Packet};
+
+#[cfg(feature = "tracing")]
+pub(crate) use spans::*;
+
+#[cfg(not(feature = "tracing"))]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Span;
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn connect_span(_config: &RconClientConfig) -> Span {
+    Span
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn auth_span(_config: &RconClientConfig) -> Span {
+    Span
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn execute_span(_config: &RconClientConfig) -> Span {
+    Span
+}
+
+/// Records a field on the span the caller is running in.
+#[cfg(not(feature = "tracing"))]
+#[inline]
+pub(crate) fn record(_field: &'static str, _value: i64) {}
+
+/// Runs `fut` in `span`, then records how long it took and any error on the span.
+#[cfg(not(feature = "tracing"))]
+#[inline]
+pub(crate) async fn in_span<T>(_span: Span, fut: impl Future<Output = Result<T, RconError>>) -> Result<T, RconError> {
+    fut.await
+}
+
+/// A command of `bytes` bytes is being sent with `cmd_id`.
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn command_sent(cmd_id: i32, bytes: usize) {
+    log::debug!("Executing command with id {:?} ({} bytes)", cmd_id, bytes);
+}
+
+/// `pkt` was received. Only its id, type and size are recorded.
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn packet_received(pkt: &Packet) {
+    log::debug!("Received {:?} packet with id {:?} ({} bytes)", pkt.packet_type, pkt.id, pkt.body.len());
+}
+
+#[cfg(feature = "tracing")]
+mod spans {
+    use std::future::Future;
+    use std::time::Instant;
+
+    use tracing::field::{display, Empty};
+    use tracing::{info_span, Instrument};
+
+    use crate::client_config::RconClientConfig;
+    use crate::errors::RconError;
+    use crate::packet::Packet;
+
+    pub(crate) type Span = tracing::Span;
+
+    pub(crate) fn connect_span(config: &RconClientConfig) -> Span {
+        info_span!("rcon.connect", server = %config.server(), duration_ms = Empty, error = Empty)
+    }
+
+    pub(crate) fn auth_span(config: &RconClientConfig) -> Span {
+        info_span!("rcon.authenticate",
+            server = %config.server(), cmd_id = Empty, packets = Empty, duration_ms = Empty, error = Empty)
+    }
+
+    pub(crate) fn execute_span(config: &RconClientConfig) -> Span {
+        info_span!("rcon.execute",
+            server = %config.server(), cmd_id = Empty, attempt = Empty, packets = Empty, duration_ms = Empty, error = Empty)
+    }
+
+    pub(crate) fn record(field: &'static str, value: i64) {
+        Span::current().record(field, value);
+    }
+
+    pub(crate) async fn in_span<T>(span: Span, fut: impl Future<Output = Result<T, RconError>>) -> Result<T, RconError> {
+        let start = Instant::now();
+        let res = fut.instrument(span.clone()).await;
+        span.record("duration_ms", start.elapsed().as_millis() as u64);
+        if let Err(e) = &res {
+            span.record("error", display(e));
+        }
+        res
+    }
+
+    pub(crate) fn command_sent(cmd_id: i32, bytes: usize) {
+        tracing::debug!(cmd_id, bytes, "Executing command");
+    }
+
+    pub(crate) fn packet_received(pkt: &Packet) {
+        tracing::debug!(id = pkt.id, packet_type = ?pkt.packet_type, bytes = pkt.body.len(), "Received packet");
+    }
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use std::collections::HashMap;
+    use std::fmt;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io::DuplexStream;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing_core::span::Current;
+    use tracing::{Event, Metadata, Subscriber};
+
+    use crate::client::RconClient;
+    use crate::connect::Connector;
+    use crate::server::RconServer;
+    use crate::test_support::{self, Echo};
+    use crate::RconClientConfig;
+
+    type Fields = HashMap<String, String>;
+
+    /// Collects the name and fields of every span, in creation order, the fields of every event, and tracks the entered spans.
+    #[derive(Default, Clone)]
+    struct Recorder {
+        spans: Arc<Mutex<Vec<(&'static str, Fields)>>>,
+        events: Arc<Mutex<Vec<Fields>>>,
+        metadata: Arc<Mutex<Vec<&'static Metadata<'static>>>>,
+        entered: Arc<Mutex<Vec<Id>>>,
+        next_id: Arc<AtomicU64>,
+    }
+
+    struct Collect<'a>(&'a mut Fields);
+
+    impl Visit for Collect<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0.insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut fields = Fields::new();
+            span.record(&mut Collect(&mut fields));
+            self.spans.lock().unwrap().push((span.metadata().name(), fields));
+            self.metadata.lock().unwrap().push(span.metadata());
+            Id::from_u64(self.next_id.fetch_add(1, Ordering::SeqCst) + 1)
+        }
+
+        fn record(&self, span: &Id, values: &Record<'_>) {
+            let mut spans = self.spans.lock().unwrap();
+            values.record(&mut Collect(&mut spans[span.into_u64() as usize - 1].1));
+        }
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+        fn event(&self, event: &Event<'_>) {
+            let mut fields = Fields::new();
+            event.record(&mut Collect(&mut fields));
+            self.events.lock().unwrap().push(fields);
+        }
+
+        fn enter(&self, span: &Id) {
+            self.entered.lock().unwrap().push(span.clone());
+        }
+
+        fn exit(&self, _: &Id) {
+            self.entered.lock().unwrap().pop();
+        }
+
+        fn current_span(&self) -> Current {
+            match self.entered.lock().unwrap().last() {
+                Some(id) => Current::new(id.clone(), self.metadata.lock().unwrap()[id.into_u64() as usize - 1]),
+                None => Current::none(),
+            }
+        }
+    }
+
+    fn echo_connector(password: &str) -> impl Connector<DuplexStream> {
+        test_support::serve_connector(RconServer::new(password.to_string(), Echo), Arc::default())
+    }
+
+    fn config() -> RconClientConfig {
+        RconClientConfig {
+            address: "game.internal".to_string(),
+            port: 27015,
+            password: "hunter2".to_string(),
+            ..test_support::config()
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_authenticate_and_execute_record_spans() {
+        let recorder = Recorder::default();
+        let _guard = tracing::subscriber::set_default(recorder.clone());
+
+        let mut client = RconClient::connect_with(config(), echo_connector("hunter2")).await.unwrap();
+        client.execute("status").await.unwrap();
+
+        let spans = recorder.spans.lock().unwrap();
+        let names: Vec<_> = spans.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, ["rcon.connect", "rcon.authenticate", "rcon.execute"]);
+
+        let (_, auth) = &spans[1];
+        assert_eq!(auth["server"], "game.internal:27015");
+        assert_eq!(auth["cmd_id"], "1");
+        // An empty response value, then the auth response
+        assert_eq!(auth["packets"], "2");
+
+        let (_, exec) = &spans[2];
+        assert_eq!(exec["cmd_id"], "2");
+        assert_eq!(exec["attempt"], "1");
+        assert_eq!(exec["packets"], "1");
+        assert!(exec.contains_key("duration_ms"));
+        assert!(!exec.contains_key("error"));
+
+        for (_, fields) in spans.iter() {
+            assert!(fields.values().all(|v| !v.contains("hunter2")), "{:?}", fields);
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_authentication_records_error() {
+        let recorder = Recorder::default();
+        let _guard = tracing::subscriber::set_default(recorder.clone());
+
+        assert!(RconClient::connect_with(config(), echo_connector("other")).await.is_err());
+
+        let spans = recorder.spans.lock().unwrap();
+        assert_eq!(spans[0].1["error"], "authentication failed");
+        assert_eq!(spans[1].1["error"], "authentication failed");
+    }
+
+    #[tokio::test]
+    async fn events_record_ids_and_sizes_but_not_command_bodies() {
+        let recorder = Recorder::default();
+        let _guard = tracing::subscriber::set_default(recorder.clone());
+
+        let mut client = RconClient::connect_with(config(), echo_connector("hunter2")).await.unwrap();
+        client.execute("kick griefer").await.unwrap();
+
+        let events = recorder.events.lock().unwrap();
+        let sent = events.iter().find(|e| e["message"] == "Executing command").unwrap();
+        assert_eq!((sent["cmd_id"].as_str(), sent["bytes"].as_str()), ("2", "12"));
+        assert!(events.iter().any(|e| e["message"] == "Received packet" && e["id"] == "2" && e["bytes"] == "12"));
+
+        for fields in events.iter() {
+            assert!(fields.values().all(|v| !v.contains("griefer") && !v.contains("hunter2")), "{:?}", fields);
+        }
+    }
+}
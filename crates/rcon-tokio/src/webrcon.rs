@@ -0,0 +1,362 @@
+//! webrcon.rs
+//!
+//! WebRCON client, as used by Facepunch's Rust and some newer titles.
+//!
+//! WebRCON runs over a WebSocket at `ws://address:port/password`, and exchanges JSON text frames:
+//! - client -> server: `{"Identifier": 1, "Message": "status", "Name": "WebRcon"}`
+//! - server -> client: `{"Identifier": 1, "Message": "...", "Type": "Generic", "Stacktrace": ""}`
+//!
+//! Responses carry the identifier of the command they answer. Console output and chat which the
+//! server pushes unprompted carry an identifier no command is waiting on, and are delivered to subscribers.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+
+use futures_util::stream::{SplitSink, Stream, StreamExt};
+use futures_util::SinkExt;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, oneshot, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use crate::{client_config::RconClientConfig, errors::RconError};
+
+const CLIENT_NAME: &str = "WebRcon";
+const DEFAULT_NEXT_ID: i32 = 1;
+const EVENT_CHANNEL_CAPACITY: usize = 256;
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+/// Commands waiting on a response, by identifier. `None` once the connection has closed.
+type Pending = StdMutex<Option<HashMap<i32, oneshot::Sender<WebRconMessage>>>>;
+
+/// A message received from the server, either a command response or an unsolicited console or chat message.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct WebRconMessage {
+    pub identifier: i32,
+    pub message: String,
+    #[serde(rename = "Type", default)]
+    pub message_type: String,
+    #[serde(default)]
+    pub stacktrace: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct WebRconRequest<'a> {
+    identifier: i32,
+    message: &'a str,
+    name: &'a str,
+}
+
+#[derive(Debug)]
+struct Shared {
+    sink: Mutex<SplitSink<WsStream, Message>>,
+    pending: Arc<Pending>,
+    /// Only the reader holds the sender, so subscribers see the channel close with the connection.
+    events: broadcast::WeakSender<WebRconMessage>,
+    next_id: AtomicI32,
+    client_config: RconClientConfig,
+    reader: JoinHandle<()>,
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// A cloneable WebRCON client. Commands from clones may be in flight concurrently.
+/// The client does not reconnect, once the connection closes every command fails.
+#[derive(Debug, Clone)]
+pub struct WebRconClient {
+    shared: Arc<Shared>,
+}
+
+impl WebRconClient {
+    pub async fn connect(client_config: RconClientConfig) -> Result<Self, RconError> {
+        let url = format!(
+            "ws://{}:{}/{}",
+            client_config.address,
+            client_config.port,
+            encode_path_segment(&client_config.password),
+        );
+
+        log::debug!("Connecting to WebRCON server at {}:{}", client_config.address, client_config.port);
+        let (ws, _) = timeout(client_config.io_timeout, tokio_tungstenite::connect_async(url))
+            .await
+            .map_err(|_| RconError::Timeout)?
+            .map_err(map_ws_error)?;
+
+        let (sink, stream) = ws.split();
+        let pending = Arc::new(StdMutex::new(Some(HashMap::new())));
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        let weak_events = events.downgrade();
+        let reader = tokio::spawn(read_loop(stream, Arc::clone(&pending), events));
+
+        Ok(WebRconClient {
+            shared: Arc::new(Shared {
+                sink: Mutex::new(sink),
+                pending,
+                events: weak_events,
+                next_id: AtomicI32::new(DEFAULT_NEXT_ID),
+                client_config,
+                reader,
+            }),
+        })
+    }
+
+    /// Executes a command, waiting up to `io_timeout` for the response with its identifier.
+    pub async fn execute(&self, command: &str) -> Result<String, RconError> {
+        let id = self.alloc_id();
+        log::debug!("Executing WebRCON command with identifier {} ({} bytes)", id, command.len());
+        let (tx, rx) = oneshot::channel();
+
+        match self.shared.pending.lock().unwrap().as_mut() {
+            Some(pending) => { pending.insert(id, tx); },
+            None => return Err(RconError::ClientError("Connection closed".to_string())),
+        }
+        let _pending = PendingGuard { pending: &self.shared.pending, id };
+
+        let request = serde_json::to_string(&WebRconRequest { identifier: id, message: command, name: CLIENT_NAME })
+            .map_err(|e| RconError::Protocol(e.to_string()))?;
+
+        let io_timeout = self.shared.client_config.io_timeout;
+        {
+            let mut sink = self.shared.sink.lock().await;
+            timeout(io_timeout, sink.send(Message::text(request)))
+                .await
+                .map_err(|_| RconError::Timeout)?
+                .map_err(map_ws_error)?;
+        }
+
+        match timeout(io_timeout, rx).await {
+            Ok(Ok(msg)) => Ok(msg.message),
+            Ok(Err(_)) => Err(RconError::ClientError("Connection closed".to_string())),
+            Err(_) => Err(RconError::Timeout),
+        }
+    }
+
+    /// Subscribes to messages the server sends without a matching command, eg: console output and chat.
+    /// Only messages received after subscribing are delivered. Receivers see `RecvError::Closed` once
+    /// the connection closes.
+    pub fn subscribe(&self) -> broadcast::Receiver<WebRconMessage> {
+        match self.shared.events.upgrade() {
+            Some(events) => events.subscribe(),
+            None => broadcast::channel(1).1,
+        }
+    }
+
+    /// Like `subscribe`, as a `Stream`, which ends when the connection closes.
+    /// Messages missed by a slow consumer are skipped.
+    pub fn messages(&self) -> impl Stream<Item = WebRconMessage> + Send + 'static {
+        futures_util::stream::unfold(self.subscribe(), |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(msg) => return Some((msg, rx)),
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        log::warn!("WebRCON subscriber lagged, skipped {} messages", n);
+                    },
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+
+    /// Whether the connection has closed, after which every command fails.
+    pub fn is_closed(&self) -> bool {
+        self.shared.pending.lock().unwrap().is_none()
+    }
+
+    fn alloc_id(&self) -> i32 {
+        self.shared.next_id
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |id| {
+                Some(if id >= i32::MAX - 10 { DEFAULT_NEXT_ID } else { id + 1 })
+            })
+            .unwrap()
+    }
+}
+
+struct PendingGuard<'a> {
+    pending: &'a Pending,
+    id: i32,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(pending) = self.pending.lock().unwrap().as_mut() {
+            pending.remove(&self.id);
+        }
+    }
+}
+
+async fn read_loop(
+    mut stream: futures_util::stream::SplitStream<WsStream>,
+    pending: Arc<Pending>,
+    events: broadcast::Sender<WebRconMessage>,
+) {
+    while let Some(frame) = stream.next().await {
+        let text = match frame {
+            Ok(Message::Text(text)) => text,
+            Ok(Message::Close(_)) => break,
+            Ok(other) => {
+                log::debug!("Ignoring non-text WebRCON frame: {:?}", other);
+                continue;
+            },
+            Err(e) => {
+                log::warn!("WebRCON connection failed: {}", e);
+                break;
+            },
+        };
+
+        let msg: WebRconMessage = match serde_json::from_str(&text) {
+            Ok(msg) => msg,
+            Err(e) => {
+                log::warn!("Ignoring malformed WebRCON message: {}", e);
+                continue;
+            },
+        };
+
+        let waiter = pending.lock().unwrap().as_mut().and_then(|p| p.remove(&msg.identifier));
+        match waiter {
+            Some(tx) => { let _ = tx.send(msg); },
+            None => {
+                log::debug!("Received unsolicited WebRCON message: {:?}", msg);
+                let _ = events.send(msg);
+            },
+        }
+    }
+
+    log::debug!("WebRCON connection closed");
+    pending.lock().unwrap().take();
+}
+
+fn map_ws_error(e: tungstenite::Error) -> RconError {
+    match e {
+        tungstenite::Error::Io(e) => RconError::Io(e),
+        tungstenite::Error::Http(resp) if matches!(resp.status().as_u16(), 401 | 403) => RconError::AuthFailed,
+        e => RconError::Protocol(e.to_string()),
+    }
+}
+
+/// Percent-encodes everything but unreserved characters, so the password is a single path segment.
+fn encode_path_segment(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use serde_json::json;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+
+    /// A WebRCON stand-in which pushes a chat message ahead of every response.
+    async fn start_server(password: &'static str) -> RconClientConfig {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    #[allow(clippy::result_large_err)] // the error type is fixed by tungstenite's callback
+                    let check_password = |req: &Request, resp: Response| {
+                        if req.uri().path() == format!("/{}", password) {
+                            Ok(resp)
+                        } else {
+                            let mut resp = tungstenite::http::Response::new(None);
+                            *resp.status_mut() = tungstenite::http::StatusCode::UNAUTHORIZED;
+                            Err(resp)
+                        }
+                    };
+                    let Ok(mut ws) = tokio_tungstenite::accept_hdr_async(stream, check_password).await else { return };
+
+                    while let Some(Ok(Message::Text(text))) = ws.next().await {
+                        let req: serde_json::Value = serde_json::from_str(&text).unwrap();
+                        if req["Message"] == "quit" {
+                            let _ = ws.close(None).await;
+                            return;
+                        }
+                        let chat = json!({ "Identifier": 0, "Message": "[CHAT] hi", "Type": "Chat" });
+                        let resp = json!({
+                            "Identifier": req["Identifier"],
+                            "Message": format!("echo: {}", req["Message"].as_str().unwrap()),
+                            "Type": "Generic",
+                            "Stacktrace": "",
+                        });
+                        ws.send(Message::text(chat.to_string())).await.unwrap();
+                        ws.send(Message::text(resp.to_string())).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        RconClientConfig::new(addr.ip().to_string(), addr.port(), password.to_string())
+            .io_timeout(Duration::from_secs(1))
+    }
+
+    #[test]
+    fn encode_path_segment_escapes_reserved_characters() {
+        assert_eq!(encode_path_segment("abc-123"), "abc-123");
+        assert_eq!(encode_path_segment("p@ss/word"), "p%40ss%2Fword");
+    }
+
+    #[tokio::test]
+    async fn execute_matches_responses_by_identifier() {
+        let config = start_server("pw").await;
+        let client = WebRconClient::connect(config).await.unwrap();
+
+        let (a, b) = tokio::join!(client.execute("status"), client.execute("players"));
+
+        assert_eq!(a.unwrap(), "echo: status");
+        assert_eq!(b.unwrap(), "echo: players");
+    }
+
+    #[tokio::test]
+    async fn unsolicited_messages_are_delivered_to_subscribers() {
+        let config = start_server("pw").await;
+        let client = WebRconClient::connect(config).await.unwrap();
+        let mut messages = Box::pin(client.messages());
+
+        client.execute("status").await.unwrap();
+        let msg = timeout(Duration::from_secs(1), messages.next()).await.unwrap().unwrap();
+
+        assert_eq!(msg.identifier, 0);
+        assert_eq!(msg.message, "[CHAT] hi");
+        assert_eq!(msg.message_type, "Chat");
+    }
+
+    #[tokio::test]
+    async fn subscribers_see_connection_close() {
+        let config = start_server("pw").await;
+        let client = WebRconClient::connect(config).await.unwrap();
+        let mut messages = Box::pin(client.messages());
+
+        assert!(client.execute("quit").await.is_err());
+
+        assert_eq!(timeout(Duration::from_secs(1), messages.next()).await.unwrap(), None);
+        assert!(client.is_closed());
+        assert!(matches!(client.subscribe().try_recv(), Err(broadcast::error::TryRecvError::Closed)));
+    }
+
+    #[tokio::test]
+    async fn connect_fails_on_wrong_password() {
+        let config = start_server("pw").await;
+
+        let res = WebRconClient::connect(RconClientConfig { password: "wrong".to_string(), ..config }).await;
+
+        assert!(matches!(res, Err(RconError::AuthFailed)));
+    }
+}